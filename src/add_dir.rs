@@ -0,0 +1,152 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use ignore::overrides::OverrideBuilder;
+use ignore::WalkBuilder;
+
+pub const IGNORE_FILE_NAME: &str = ".shiromanaignore";
+
+pub struct WalkOptions<'a> {
+    pub include: &'a [String],
+    pub exclude: &'a [String],
+    pub hidden: bool,
+}
+
+// Name of the series a file found under `root` goes to, like `root/sub/dir`.
+pub fn dir_series_name(root: &Path, file: &Path) -> String {
+    let root_name = root
+        .canonicalize()
+        .ok()
+        .and_then(|v| {
+            v.file_name()
+                .map(|v| v.to_str().unwrap_or_default().to_string())
+        })
+        .unwrap_or_default();
+    let rel = file
+        .parent()
+        .and_then(|v| v.strip_prefix(root).ok())
+        .map(|v| v.to_str().unwrap_or_default().to_string())
+        .unwrap_or_default();
+    if rel.is_empty() {
+        root_name
+    } else {
+        root_name + "/" + &rel
+    }
+}
+
+// Every file under `root`, sorted by path so that series keep a stable order.
+pub fn walk_dir(root: &Path, opt: &WalkOptions) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let mut overrides = OverrideBuilder::new(root);
+    for glob in opt.include.iter() {
+        overrides.add(glob)?;
+    }
+    for glob in opt.exclude.iter() {
+        overrides.add(&format!("!{}", glob))?;
+    }
+    // Included globs win over the hidden filter of the walker, so hidden
+    // entries are left out here as well.
+    let hidden = opt.hidden;
+    let walker = WalkBuilder::new(root)
+        .hidden(!opt.hidden)
+        .filter_entry(move |e| {
+            hidden || e.depth() == 0 || !e.file_name().to_string_lossy().starts_with('.')
+        })
+        .parents(false)
+        .ignore(false)
+        .git_ignore(false)
+        .git_global(false)
+        .git_exclude(false)
+        .add_custom_ignore_filename(IGNORE_FILE_NAME)
+        .overrides(overrides.build()?)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build();
+    let mut files = vec![];
+    for entry in walker {
+        let entry = entry?;
+        let is_file = matches!(entry.file_type(), Some(v) if v.is_file());
+        if is_file && entry.file_name() != IGNORE_FILE_NAME {
+            files.push(entry.into_path());
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tree() -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for name in [
+            "a.jpg",
+            "b.png",
+            "notes.txt",
+            ".hidden.jpg",
+            "sub/c.jpg",
+            "sub/deep/d.jpg",
+            "skipped/e.jpg",
+        ]
+        .iter()
+        {
+            let path = dir.path().join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, b"").unwrap();
+        }
+        std::fs::write(dir.path().join(IGNORE_FILE_NAME), "skipped/\n").unwrap();
+        dir
+    }
+
+    fn walk(root: &Path, include: &[&str], exclude: &[&str], hidden: bool) -> Vec<String> {
+        let include: Vec<String> = include.iter().map(|v| v.to_string()).collect();
+        let exclude: Vec<String> = exclude.iter().map(|v| v.to_string()).collect();
+        let opt = WalkOptions {
+            include: &include,
+            exclude: &exclude,
+            hidden,
+        };
+        walk_dir(root, &opt)
+            .unwrap()
+            .iter()
+            .map(|v| v.strip_prefix(root).unwrap().to_str().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn walks_in_order() {
+        let dir = tree();
+        assert_eq!(
+            walk(dir.path(), &[], &[], false),
+            vec!["a.jpg", "b.png", "notes.txt", "sub/c.jpg", "sub/deep/d.jpg"]
+        );
+        assert_eq!(walk(dir.path(), &[], &[], true)[0], ".hidden.jpg");
+    }
+
+    #[test]
+    fn globs() {
+        let dir = tree();
+        assert_eq!(
+            walk(dir.path(), &["*.jpg"], &[], false),
+            vec!["a.jpg", "sub/c.jpg", "sub/deep/d.jpg"]
+        );
+        assert_eq!(walk(dir.path(), &["*.jpg"], &[], true)[0], ".hidden.jpg");
+        assert_eq!(
+            walk(dir.path(), &["*.jpg"], &["deep"], false),
+            vec!["a.jpg", "sub/c.jpg"]
+        );
+        assert_eq!(
+            walk(dir.path(), &[], &["*.txt", "sub"], false),
+            vec!["a.jpg", "b.png"]
+        );
+    }
+
+    #[test]
+    fn series_names() {
+        let dir = tree();
+        let root = dir.path().join("sub");
+        assert_eq!(dir_series_name(&root, &root.join("c.jpg")), "sub");
+        assert_eq!(
+            dir_series_name(&root, &root.join("deep").join("d.jpg")),
+            "sub/deep"
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::Path;
+
+use image::io::Reader as ImageReader;
+use image::{GenericImageView, ImageFormat};
+use shiromana_rs::library::Library;
+use shiromana_rs::media::{ImageDetail, MediaDetail, MediaType};
+use shiromana_rs::misc::Error as LibError;
+
+use crate::image_meta::read_metadata;
+use crate::phash::{dhash, to_hex, PERCEPTUAL_HASH_KEY};
+
+#[derive(Debug)]
+pub enum ImageError {
+    Unrecognized(String),
+    Corrupted(String, String),
+    Truncated(String),
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::Unrecognized(file) => write!(f, "{} is not a recognized image.", file),
+            ImageError::Corrupted(file, reason) => {
+                write!(f, "{} is corrupted due to {}.", file, reason)
+            }
+            ImageError::Truncated(file) => write!(f, "{} is truncated.", file),
+        }
+    }
+}
+
+impl Error for ImageError {}
+
+// Decoders are lenient with missing trailing data, so check the end marker of
+// formats which have one to catch half-downloaded files.
+fn is_truncated(file: &Path, format: ImageFormat) -> Result<bool, Box<dyn Error>> {
+    let trailer: &[u8] = match format {
+        ImageFormat::Jpeg => &[0xFF, 0xD9],
+        ImageFormat::Png => &[0xAE, 0x42, 0x60, 0x82],
+        ImageFormat::Gif => &[0x3B],
+        _ => return Ok(false),
+    };
+    let mut f = File::open(file)?;
+    let len = f.metadata()?.len();
+    let tail_len = std::cmp::min(len, 1024);
+    f.seek(SeekFrom::Start(len - tail_len))?;
+    let mut tail = vec![];
+    f.read_to_end(&mut tail)?;
+    // Some encoders pad the file after the end marker.
+    while tail.last() == Some(&0x00) {
+        tail.pop();
+    }
+    Ok(!tail.ends_with(trailer))
+}
+
+pub fn probe_image(file: &Path) -> Result<ImageDetail, Box<dyn Error>> {
+    let name = file.to_str().unwrap_or_default().to_string();
+    let reader = ImageReader::open(file)?.with_guessed_format()?;
+    let format = match reader.format() {
+        Some(v) => v,
+        None => return Err(ImageError::Unrecognized(name).into()),
+    };
+    if is_truncated(file, format)? {
+        return Err(ImageError::Truncated(name).into());
+    }
+    let image = reader
+        .decode()
+        .map_err(|e| ImageError::Corrupted(name, e.to_string()))?;
+    let mut extra = read_metadata(file);
+    extra.insert(PERCEPTUAL_HASH_KEY.to_string(), to_hex(dhash(&image)));
+    Ok(ImageDetail {
+        width: image.width(),
+        height: image.height(),
+        color_depth: image.color().bits_per_pixel(),
+        format: format!("{:?}", format).to_ascii_uppercase(),
+        extra,
+    })
+}
+
+// Adds a file whose hash was worked out ahead, leaving it to the library
+// otherwise. Bare links have no file to hash.
+pub fn add_file(
+    lib: &mut Library,
+    file: String,
+    kind: MediaType,
+    hash: Option<String>,
+    title: Option<String>,
+    comment: Option<String>,
+) -> Result<u64, LibError> {
+    match hash {
+        Some(hash) => lib.add_media_with_hash(file, kind, None, None, title, comment, hash),
+        None => lib.add_media(file, kind, None, None, title, comment),
+    }
+}
+
+// `detail` comes from `probe_image`, which is done ahead on the import workers.
+// The media is removed again when its detail cannot be stored, so no image is
+// left in the library without one.
+pub fn add_image(
+    lib: &mut Library,
+    file: String,
+    detail: ImageDetail,
+    hash: Option<String>,
+    title: Option<String>,
+    comment: Option<String>,
+) -> Result<u64, Box<dyn Error>> {
+    let id = add_file(lib, file, MediaType::Image, hash, title, comment)?;
+    let result = lib.get_media(id).and_then(|mut media| {
+        media.detail = Some(MediaDetail::Image(detail));
+        lib.update_media(&media)
+    });
+    if let Err(e) = result {
+        lib.remove_media(id)?;
+        return Err(e.into());
+    }
+    Ok(id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Rgb};
+
+    fn write_png(dir: &Path) -> std::path::PathBuf {
+        let path = dir.join("image.png");
+        ImageBuffer::from_pixel(4, 3, Rgb([200u8, 100, 0]))
+            .save(&path)
+            .unwrap();
+        path
+    }
+
+    #[test]
+    fn probe_reads_size_and_format() {
+        let dir = tempfile::tempdir().unwrap();
+        let detail = probe_image(&write_png(dir.path())).unwrap();
+        assert_eq!((detail.width, detail.height), (4, 3));
+        assert_eq!(detail.format, "PNG");
+        assert!(detail.extra.contains_key(PERCEPTUAL_HASH_KEY));
+    }
+
+    #[test]
+    fn trailing_padding_is_not_truncation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_png(dir.path());
+        let mut data = std::fs::read(&path).unwrap();
+        data.extend_from_slice(&[0, 0, 0]);
+        std::fs::write(&path, &data).unwrap();
+        assert!(!is_truncated(&path, ImageFormat::Png).unwrap());
+    }
+
+    #[test]
+    fn missing_end_marker_is_truncation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_png(dir.path());
+        let data = std::fs::read(&path).unwrap();
+        std::fs::write(&path, &data[..data.len() - 8]).unwrap();
+        assert!(is_truncated(&path, ImageFormat::Png).unwrap());
+        match probe_image(&path) {
+            Err(e) => assert!(e.to_string().ends_with("is truncated.")),
+            Ok(_) => panic!("truncated image is accepted"),
+        }
+    }
+
+    #[test]
+    fn other_files_are_unrecognized() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        std::fs::write(&path, "not an image").unwrap();
+        assert!(probe_image(&path).is_err());
+    }
+}
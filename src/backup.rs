@@ -0,0 +1,522 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use chrono::Local;
+use ignore::WalkBuilder;
+use indicatif::{ProgressBar, ProgressStyle};
+use serde::{Deserialize, Serialize};
+use shiromana_rs::library::Library;
+use shiromana_rs::misc::HashAlgo;
+
+use crate::command::{STYLE_ERROR, STYLE_FIELD_NAME, STYLE_FIELD_VALUE};
+use crate::config::store_config;
+use crate::hasher::{hash_file, HashingReader};
+use crate::incremental::do_incremental_backup;
+use crate::journal::JOURNAL_DIR_NAME;
+use crate::output::{BackupRecord, Output, Record, RestoreRecord};
+use crate::thumb::THUMB_DIR_NAME;
+use crate::verify::{QUARANTINE_DIR_NAME, STAMPS_NAME};
+use crate::{AppConfig, Backup, LibraryProfile, Restore, DEFAULT_PROFILE};
+
+pub const MANIFEST_NAME: &str = "shiromana-backup.json";
+const MANIFEST_VERSION: u32 = 1;
+const LIBRARY_PREFIX: &str = "library";
+
+// Caches and leftovers which a restored library does without.
+const SKIPPED_DIRS: &[&str] = &[
+    THUMB_DIR_NAME,
+    JOURNAL_DIR_NAME,
+    QUARANTINE_DIR_NAME,
+    STAMPS_NAME,
+];
+
+#[derive(Serialize, Deserialize)]
+pub struct ManifestFile {
+    pub path: String,
+    pub size: u64,
+    pub hash: String,
+    /// Set in incremental backups for media, whose content is kept once in
+    /// the object store under this name instead of in the generation.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub object: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub created: String,
+    pub library_uuid: String,
+    pub library_name: String,
+    pub schema: String,
+    pub hash_algo: String,
+    pub files: Vec<ManifestFile>,
+}
+
+impl Manifest {
+    pub fn new(lib: &Library) -> Self {
+        Self {
+            version: MANIFEST_VERSION,
+            created: Local::now().to_rfc3339(),
+            library_uuid: lib.uuid.to_string(),
+            library_name: lib.get_library_name(),
+            schema: lib.get_schema(),
+            hash_algo: lib.get_hash_algo().to_string(),
+            files: vec![],
+        }
+    }
+
+    pub fn algo(&self) -> Result<HashAlgo, Box<dyn Error>> {
+        HashAlgo::from_str(&self.hash_algo).map_err(|_| {
+            format!("Unknown hash algorithm {} in the manifest.", self.hash_algo).into()
+        })
+    }
+}
+
+// Files of the library relative to its root, directories first sorted by
+// name so archives of the same library come out the same.
+pub fn library_files(lib_path: &Path) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let walker = WalkBuilder::new(lib_path)
+        .standard_filters(false)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .filter_entry(|e| {
+            e.depth() != 1
+                || !SKIPPED_DIRS
+                    .iter()
+                    .any(|d| e.file_name().to_str() == Some(d))
+        })
+        .build();
+    let mut files = vec![];
+    for entry in walker {
+        let entry = entry?;
+        if matches!(entry.file_type(), Some(v) if v.is_file()) {
+            files.push(entry.path().strip_prefix(lib_path)?.to_path_buf());
+        }
+    }
+    Ok(files)
+}
+
+// Size and modification time of a file, which change with any write to it.
+fn stamp(file: &Path) -> Option<(u64, SystemTime)> {
+    std::fs::metadata(file)
+        .ok()
+        .and_then(|v| Some((v.len(), v.modified().ok()?)))
+}
+
+// Media files are only ever added or removed, while the database and the
+// other files of the library are written in place. If none of the latter
+// changed from start to end, no writer was active in between.
+pub fn written_files(
+    lib_path: &Path,
+    files: &[PathBuf],
+    media: &[String],
+) -> Vec<(PathBuf, Option<(u64, SystemTime)>)> {
+    let media: HashSet<&Path> = media.iter().map(Path::new).collect();
+    files
+        .iter()
+        .filter(|f| !media.contains(f.as_path()))
+        .map(|f| (f.clone(), stamp(&lib_path.join(f))))
+        .collect()
+}
+
+pub fn to_archive_path(file: &Path) -> String {
+    let parts: Vec<&str> = file.iter().filter_map(|v| v.to_str()).collect();
+    format!("{}/{}", LIBRARY_PREFIX, parts.join("/"))
+}
+
+fn write_archive<F: Fn() -> bool>(
+    lib: &Library,
+    files: &[PathBuf],
+    target: &Path,
+    level: i32,
+    exit_checker: F,
+    out: &mut Output,
+) -> Result<Option<Manifest>, Box<dyn Error>> {
+    let lib_path = PathBuf::from(lib.get_path());
+    let algo = lib.get_hash_algo();
+    // Stored media are checked against their hash on the way.
+    let mut known: HashMap<String, String> = HashMap::new();
+    for id in lib.query_media("1 = 1")? {
+        let m = lib.get_media(id)?;
+        known.insert(m.filepath, m.hash);
+    }
+    let total: u64 = files
+        .iter()
+        .filter_map(|f| stamp(&lib_path.join(f)))
+        .map(|v| v.0)
+        .sum();
+    let bar = if out.is_text() && console::user_attended() {
+        ProgressBar::new(total)
+    } else {
+        ProgressBar::hidden()
+    };
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} at {bytes_per_sec}, ETA {eta} {wide_msg}")
+            .progress_chars("#>-"),
+    );
+
+    let encoder = zstd::Encoder::new(BufWriter::new(File::create(target)?), level)?;
+    let mut builder = tar::Builder::new(encoder);
+    let mut manifest = Manifest::new(lib);
+    for file in files.iter() {
+        let path = lib_path.join(file);
+        let meta = std::fs::metadata(&path)?;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(meta.len());
+        header.set_mode(0o644);
+        header.set_mtime(
+            meta.modified()?
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |v| v.as_secs()),
+        );
+        let name = to_archive_path(file);
+        bar.set_message(name.clone());
+        let mut reader =
+            HashingReader::new(bar.wrap_read(BufReader::new(File::open(&path)?)), &algo);
+        builder.append_data(&mut header, &name, &mut reader)?;
+        let hash = reader.finish();
+        let relative = file.to_str().unwrap_or_default();
+        if let Some(expected) = known.get(relative) {
+            if !expected.eq_ignore_ascii_case(&hash) {
+                let message = format!(
+                    "{} does not match its hash in the library, run verify to check it.",
+                    relative
+                );
+                out.error(message.clone(), || {
+                    bar.println(format!("{}", STYLE_ERROR.apply_to(message)))
+                });
+            }
+        }
+        manifest.files.push(ManifestFile {
+            path: name,
+            size: meta.len(),
+            hash,
+            object: None,
+        });
+        if exit_checker() {
+            bar.abandon_with_message("Interrupted.");
+            return Ok(None);
+        }
+    }
+    bar.finish_and_clear();
+    let data = serde_json::to_vec_pretty(&manifest)?;
+    let mut header = tar::Header::new_gnu();
+    header.set_size(data.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Local::now().timestamp() as u64);
+    builder.append_data(&mut header, MANIFEST_NAME, data.as_slice())?;
+    builder.into_inner()?.finish()?;
+    Ok(Some(manifest))
+}
+
+pub fn partial_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".partial");
+    path.with_file_name(name)
+}
+
+pub fn do_backup<F: Fn() -> bool>(
+    opt: Backup,
+    _cfg: AppConfig,
+    lib: &Library,
+    exit_checker: F,
+    out: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    let archive = match (&opt.incremental, &opt.archive) {
+        (Some(dir), _) => return do_incremental_backup(&opt, dir, lib, exit_checker, out),
+        (None, Some(v)) => v.clone(),
+        (None, None) => return Err("Give either an archive or --incremental.".into()),
+    };
+    if archive.exists() {
+        return Err(format!(
+            "{} is already existed.",
+            archive.to_str().unwrap_or_default()
+        )
+        .into());
+    }
+    let lib_path = PathBuf::from(lib.get_path());
+    let files = library_files(&lib_path)?;
+    let media: Vec<String> = lib
+        .query_media("1 = 1")?
+        .into_iter()
+        .filter_map(|id| lib.get_media(id).ok())
+        .map(|m| m.filepath)
+        .collect();
+    let before = written_files(&lib_path, &files, &media);
+
+    // Written aside first, so an interrupted backup never looks finished.
+    let partial = partial_path(&archive);
+    let manifest = match write_archive(lib, &files, &partial, opt.level, exit_checker, out) {
+        Ok(Some(v)) => v,
+        Ok(None) => {
+            std::fs::remove_file(&partial).unwrap_or(());
+            return Ok(());
+        }
+        Err(e) => {
+            std::fs::remove_file(&partial).unwrap_or(());
+            return Err(e);
+        }
+    };
+    if written_files(&lib_path, &files, &media) != before {
+        std::fs::remove_file(&partial).unwrap_or(());
+        return Err(
+            "The library was written to during the backup, try again when it is not in use.".into(),
+        );
+    }
+    std::fs::rename(&partial, &archive)?;
+
+    let size = std::fs::metadata(&archive)?.len();
+    out.emit(
+        Record::Backup(BackupRecord {
+            archive: archive.to_str().unwrap_or_default().to_string(),
+            library_uuid: manifest.library_uuid.clone(),
+            files: manifest.files.len(),
+            size,
+        }),
+        || {
+            println!(
+                "{} {} {}",
+                STYLE_FIELD_NAME.apply_to("Backed up"),
+                STYLE_FIELD_VALUE.apply_to(format!("{} files", manifest.files.len())),
+                STYLE_FIELD_NAME.apply_to(format!("to {}", archive.to_str().unwrap_or_default()))
+            )
+        },
+    );
+    Ok(())
+}
+
+// Entries must stay inside the directory they are restored into.
+fn safe_relative(path: &Path) -> Option<PathBuf> {
+    let mut parts = path.components();
+    if parts.next() != Some(Component::Normal(LIBRARY_PREFIX.as_ref())) {
+        return None;
+    }
+    let rest: PathBuf = parts.clone().collect();
+    if rest.as_os_str().is_empty() || parts.any(|c| !matches!(c, Component::Normal(_))) {
+        return None;
+    }
+    Some(rest)
+}
+
+// Unpack into `target` and check every file against the manifest. The
+// manifest is the last entry of the archive, so the files are hashed once
+// they are all out.
+fn unpack(archive: &Path, target: &Path) -> Result<Manifest, Box<dyn Error>> {
+    let mut tar = tar::Archive::new(zstd::Decoder::new(File::open(archive)?)?);
+    let mut unpacked: Vec<String> = vec![];
+    let mut manifest: Option<Manifest> = None;
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let name = entry.path()?.to_path_buf();
+        if name == Path::new(MANIFEST_NAME) {
+            let mut data = String::new();
+            entry.read_to_string(&mut data)?;
+            manifest = Some(serde_json::from_str(&data)?);
+            continue;
+        }
+        let relative = safe_relative(&name).ok_or_else(|| {
+            format!(
+                "{} is not a file of a library backup.",
+                name.to_str().unwrap_or_default()
+            )
+        })?;
+        let path = target.join(&relative);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::io::copy(&mut entry, &mut BufWriter::new(File::create(&path)?))?;
+        unpacked.push(to_archive_path(&relative));
+    }
+    let manifest = manifest
+        .ok_or("The archive has no manifest, it is not a library backup or it is cut short.")?;
+    if manifest.version > MANIFEST_VERSION {
+        return Err(format!(
+            "The backup is made by a newer version, manifest version {}.",
+            manifest.version
+        )
+        .into());
+    }
+    let algo = manifest.algo()?;
+    let unpacked_set: HashSet<&str> = unpacked.iter().map(|v| v.as_str()).collect();
+    for file in manifest.files.iter() {
+        if !unpacked_set.contains(file.path.as_str()) {
+            return Err(format!("{} is missing from the archive.", file.path).into());
+        }
+        let path = target.join(safe_relative(Path::new(&file.path)).unwrap_or_default());
+        let size = std::fs::metadata(&path)?.len();
+        if size != file.size || !hash_file(&path, &algo)?.eq_ignore_ascii_case(&file.hash) {
+            return Err(format!("{} is damaged in the archive.", file.path).into());
+        }
+    }
+    let listed: HashSet<&str> = manifest.files.iter().map(|f| f.path.as_str()).collect();
+    if let Some(extra) = unpacked.iter().find(|v| !listed.contains(v.as_str())) {
+        return Err(format!("{} is in the archive but not in its manifest.", extra).into());
+    }
+    Ok(manifest)
+}
+
+pub fn do_restore(
+    opt: Restore,
+    mut cfg: AppConfig,
+    library: Option<&str>,
+    config_path: &Path,
+    out: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    let dir = opt.to.clone();
+    std::fs::create_dir_all(&dir)?;
+    let partial = dir.join(format!("restore-{}.partial", std::process::id()));
+    if partial.exists() {
+        std::fs::remove_dir_all(&partial)?;
+    }
+    let manifest = match unpack(&opt.archive, &partial) {
+        Ok(v) => v,
+        Err(e) => {
+            std::fs::remove_dir_all(&partial).unwrap_or(());
+            return Err(e);
+        }
+    };
+    let target = dir.join(manifest.library_name.clone() + ".mlib");
+    if target.exists() {
+        std::fs::remove_dir_all(&partial).unwrap_or(());
+        return Err(format!(
+            "{} is already existed.",
+            target.to_str().unwrap_or_default()
+        )
+        .into());
+    }
+    std::fs::rename(&partial, &target)?;
+    // A library which does not open as the one the manifest names is taken
+    // out again.
+    let opened = Library::open(target.to_str().unwrap_or_default().to_string())
+        .map_err(|e| e.into())
+        .and_then(|lib| -> Result<(), Box<dyn Error>> {
+            if lib.uuid.to_string() != manifest.library_uuid {
+                return Err(format!(
+                    "Restored library has UUID {} instead of {}.",
+                    lib.uuid, manifest.library_uuid
+                )
+                .into());
+            }
+            Ok(())
+        });
+    if let Err(e) = opened {
+        std::fs::remove_dir_all(&target).unwrap_or(());
+        return Err(e);
+    }
+
+    // The profile in use is pointed at the restored library, --library may
+    // also name a new one. Without any library it becomes the default.
+    let name = match library {
+        Some(v) => v.to_string(),
+        None if cfg.default_library.is_empty() => DEFAULT_PROFILE.to_string(),
+        None => cfg.default_library.clone(),
+    };
+    if !cfg.libraries.contains_key(&cfg.default_library) {
+        cfg.default_library = name.clone();
+    }
+    cfg.libraries.insert(
+        name.clone(),
+        LibraryProfile {
+            library_path: dir.to_str().unwrap_or_default().to_string(),
+            library_name: manifest.library_name.clone(),
+        },
+    );
+    store_config(config_path, &cfg)?;
+    out.emit(
+        Record::Restore(RestoreRecord {
+            archive: opt.archive.to_str().unwrap_or_default().to_string(),
+            path: target.to_str().unwrap_or_default().to_string(),
+            library_uuid: manifest.library_uuid.clone(),
+            files: manifest.files.len(),
+        }),
+        || {
+            println!(
+                "{} {} {}",
+                STYLE_FIELD_NAME.apply_to("Restored"),
+                STYLE_FIELD_VALUE.apply_to(format!("{} files", manifest.files.len())),
+                STYLE_FIELD_NAME.apply_to(format!(
+                    "to {}, which is now the library {}.",
+                    target.to_str().unwrap_or_default(),
+                    name
+                ))
+            )
+        },
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn archive_paths() {
+        let file = Path::new("ab").join("cd.jpg");
+        assert_eq!(to_archive_path(&file), "library/ab/cd.jpg");
+        assert_eq!(
+            safe_relative(Path::new(&to_archive_path(&file))),
+            Some(file)
+        );
+        assert_eq!(safe_relative(Path::new("library")), None);
+        assert_eq!(safe_relative(Path::new("other/a.jpg")), None);
+        assert_eq!(safe_relative(Path::new("library/../a.jpg")), None);
+        assert_eq!(safe_relative(Path::new("/library/a.jpg")), None);
+        assert_eq!(
+            partial_path(Path::new("out/lib.tar.zst")),
+            Path::new("out/lib.tar.zst.partial")
+        );
+    }
+
+    #[test]
+    fn files_of_library() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["ab", THUMB_DIR_NAME, QUARANTINE_DIR_NAME].iter() {
+            std::fs::create_dir(dir.path().join(name)).unwrap();
+            std::fs::write(dir.path().join(name).join("x.jpg"), b"x").unwrap();
+        }
+        std::fs::write(dir.path().join("library.db"), b"db").unwrap();
+        std::fs::write(dir.path().join(STAMPS_NAME), b"{}").unwrap();
+        let files = library_files(dir.path()).unwrap();
+        assert_eq!(
+            files,
+            vec![PathBuf::from("ab/x.jpg"), PathBuf::from("library.db")]
+        );
+        // Media files are left out of the check for writers.
+        let written = written_files(dir.path(), &files, &["ab/x.jpg".to_string()]);
+        assert_eq!(written.len(), 1);
+        assert_eq!(written[0].0, PathBuf::from("library.db"));
+        assert_eq!(written[0].1.unwrap().0, 2);
+    }
+
+    fn archive(dir: &Path, entries: &[(&str, &[u8])]) -> PathBuf {
+        let path = dir.join("backup.tar.zst");
+        let encoder = zstd::Encoder::new(File::create(&path).unwrap(), 0).unwrap();
+        let mut builder = tar::Builder::new(encoder);
+        for (name, data) in entries.iter() {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, *data).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap();
+        path
+    }
+
+    #[test]
+    fn bad_archives() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("out");
+        let path = archive(dir.path(), &[("library/a.jpg", b"a")]);
+        let e = unpack(&path, &target).err().unwrap();
+        assert!(e.to_string().starts_with("The archive has no manifest"));
+        let path = archive(dir.path(), &[("elsewhere/a.jpg", b"a")]);
+        let e = unpack(&path, &target).err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            "elsewhere/a.jpg is not a file of a library backup."
+        );
+    }
+}
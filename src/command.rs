@@ -1,33 +1,150 @@
-use crate::{Add, AppConfig, Create, Info};
+use crate::add_dir::{dir_series_name, walk_dir, WalkOptions};
+use crate::add_image::{add_file, add_image};
+use crate::fetch::{find_origin, forget_origin, Origin};
+use crate::image_meta::{capture_time, CAPTURE_TIME_KEY};
+use crate::journal::{ImportPlan, Journal};
+use crate::output::{
+    AddRecord, MediaRecord, MembershipRecord, Output, Record, RemoveRecord, SetRecord,
+};
+use crate::pipeline::{Pipeline, Prepared};
+use crate::preview::show_preview;
+use crate::progress::{ImportProgress, ImportStatus};
+use crate::query::Query;
+use crate::{Add, AppConfig, Create, CreateType, Info, List, ListView, Remove, Search, SortKey};
 use console::{style, Style, StyledObject};
 use dialoguer::{theme::ColorfulTheme, Confirm, Input, Validator};
 use humansize::{file_size_opts, FileSize};
 use mime;
 use shiromana_rs::library::{Library, LibrarySummary, MediaSetType};
-use shiromana_rs::media::{Media, MediaDetail, MediaType};
+use shiromana_rs::media::{ImageDetail, Media, MediaDetail, MediaType};
 use shiromana_rs::misc::{Error as LibError, HashAlgo, Uuid};
 use std::boxed::Box;
+use std::collections::HashSet;
 use std::convert::TryInto;
 use std::error::Error;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::exit;
 use std::str::FromStr;
 use tree_magic;
 use url::{Host, ParseError, Position, Url};
 
 lazy_static! {
-    static ref DECO_LEFT_PAR_M: StyledObject<&'static str> = style("[").black().bright();
-    static ref DECO_RIGHT_PAR_M: StyledObject<&'static str> = style("]").black().bright();
-    static ref DECO_BRANCH: StyledObject<&'static str> = style("|-").black().bright();
-    static ref STYLE_FIELD_NAME: Style = Style::new().yellow();
-    static ref STYLE_FIELD_VALUE: Style = Style::new().blue().bright();
-    static ref STYLE_ERROR: Style = Style::new().red().bright();
+    pub static ref DECO_LEFT_PAR_M: StyledObject<&'static str> = style("[").black().bright();
+    pub static ref DECO_RIGHT_PAR_M: StyledObject<&'static str> = style("]").black().bright();
+    pub static ref DECO_BRANCH: StyledObject<&'static str> = style("|-").black().bright();
+    pub static ref STYLE_FIELD_NAME: Style = Style::new().yellow();
+    pub static ref STYLE_FIELD_VALUE: Style = Style::new().blue().bright();
+    pub static ref STYLE_ERROR: Style = Style::new().red().bright();
 }
 
-fn is_url(s: &str) -> bool {
-    &s[0..7] == "http://" || &s[0..8] == "https://"
+pub fn is_url(s: &str) -> bool {
+    s.starts_with("http://") || s.starts_with("https://")
 }
 
-fn print_media(media: &Media, detailed: bool) {
+// Like `2 of 5`, or `? of 5` when the series does not list the media.
+fn series_position(members: &[u64], id: u64) -> String {
+    match members.iter().position(|v| *v == id) {
+        Some(v) => format!("{} of {}", v + 1, members.len()),
+        None => format!("? of {}", members.len()),
+    }
+}
+
+// Series and tags of `media` with their names, comments and the position of
+// the media inside each series.
+fn print_media_sets(lib: &Library, media: &Media) {
+    if !media.series.is_empty() {
+        println!("{}:", STYLE_FIELD_NAME.apply_to("Series"));
+        for uuid in media.series.iter() {
+            match lib.get_set(MediaSetType::Series, uuid) {
+                Ok(set) => {
+                    println!(
+                        "    {} {} {}{}{}",
+                        *DECO_BRANCH,
+                        STYLE_FIELD_VALUE.apply_to(&set.name),
+                        *DECO_LEFT_PAR_M,
+                        STYLE_FIELD_VALUE.apply_to(series_position(&set.media, media.id)),
+                        *DECO_RIGHT_PAR_M,
+                    );
+                    if let Some(v) = &set.comment {
+                        println!("        {}", STYLE_FIELD_VALUE.apply_to(v));
+                    }
+                }
+                Err(_) => println!("    {} {}", *DECO_BRANCH, STYLE_ERROR.apply_to(uuid)),
+            }
+        }
+    }
+    if !media.tag.is_empty() {
+        println!("{}:", STYLE_FIELD_NAME.apply_to("Tags"));
+        for uuid in media.tag.iter() {
+            match lib.get_set(MediaSetType::Tag, uuid) {
+                Ok(set) => {
+                    println!(
+                        "    {} {}",
+                        *DECO_BRANCH,
+                        STYLE_FIELD_VALUE.apply_to(&set.name)
+                    );
+                    if let Some(v) = &set.comment {
+                        println!("        {}", STYLE_FIELD_VALUE.apply_to(v));
+                    }
+                }
+                Err(_) => println!("    {} {}", *DECO_BRANCH, STYLE_ERROR.apply_to(uuid)),
+            }
+        }
+    }
+}
+
+fn print_image_metadata(detail: &ImageDetail) {
+    if let Some(v) = detail.extra.get(CAPTURE_TIME_KEY) {
+        println!(
+            "{}: {}",
+            STYLE_FIELD_NAME.apply_to("Capture Time"),
+            STYLE_FIELD_VALUE.apply_to(v)
+        );
+    }
+    let mut fields: Vec<(&String, &String)> = detail
+        .extra
+        .iter()
+        .filter(|(k, _)| k.starts_with("exif.") || k.starts_with("xmp."))
+        .collect();
+    if fields.is_empty() {
+        return;
+    }
+    fields.sort();
+    println!("{}", STYLE_FIELD_NAME.apply_to("Metadata"));
+    for (name, value) in fields {
+        println!(
+            "    {} {}: {}",
+            *DECO_BRANCH,
+            STYLE_FIELD_NAME.apply_to(name),
+            STYLE_FIELD_VALUE.apply_to(value)
+        );
+    }
+}
+
+fn print_origin(origin: &Origin) {
+    println!("{}", STYLE_FIELD_NAME.apply_to("Origin"));
+    for (name, value) in [
+        ("URL", Some(&origin.url)),
+        ("Fetched at", Some(&origin.fetched_at)),
+        ("ETag", origin.etag.as_ref()),
+        ("Last-Modified", origin.last_modified.as_ref()),
+        ("Content-Type", origin.content_type.as_ref()),
+    ]
+    .iter()
+    {
+        if let Some(value) = value {
+            println!(
+                "    {} {}: {}",
+                *DECO_BRANCH,
+                STYLE_FIELD_NAME.apply_to(name),
+                STYLE_FIELD_VALUE.apply_to(value)
+            );
+        }
+    }
+}
+
+// With `lib` the detailed view shows set names, without it the raw UUIDs.
+pub fn print_media(media: &Media, detailed: bool, lib: Option<&Library>) {
     if detailed {
         println!(
             "{}: {}",
@@ -90,33 +207,38 @@ fn print_media(media: &Media, detailed: bool) {
                 STYLE_FIELD_VALUE.apply_to(v)
             );
         }
-        if media.series.len() != 0 {
-            println!(
-                "{}:\n{}",
-                STYLE_FIELD_NAME.apply_to("Series UUID"),
-                STYLE_FIELD_VALUE.apply_to(
-                    &media
-                        .series
-                        .iter()
-                        .map(|u| { "    ".to_string() + &u.to_string() })
-                        .collect::<Vec<String>>()
-                        .join("\n")
-                )
-            );
-        }
-        if media.tag.len() != 0 {
-            println!(
-                "{}:\n{}",
-                STYLE_FIELD_NAME.apply_to("Tags UUID"),
-                STYLE_FIELD_VALUE.apply_to(
-                    &media
-                        .tag
-                        .iter()
-                        .map(|u| { "    ".to_string() + &u.to_string() })
-                        .collect::<Vec<String>>()
-                        .join("\n")
-                )
-            );
+        match lib {
+            Some(lib) => print_media_sets(lib, media),
+            None => {
+                if !media.series.is_empty() {
+                    println!(
+                        "{}:\n{}",
+                        STYLE_FIELD_NAME.apply_to("Series UUID"),
+                        STYLE_FIELD_VALUE.apply_to(
+                            &media
+                                .series
+                                .iter()
+                                .map(|u| { "    ".to_string() + &u.to_string() })
+                                .collect::<Vec<String>>()
+                                .join("\n")
+                        )
+                    );
+                }
+                if !media.tag.is_empty() {
+                    println!(
+                        "{}:\n{}",
+                        STYLE_FIELD_NAME.apply_to("Tags UUID"),
+                        STYLE_FIELD_VALUE.apply_to(
+                            &media
+                                .tag
+                                .iter()
+                                .map(|u| { "    ".to_string() + &u.to_string() })
+                                .collect::<Vec<String>>()
+                                .join("\n")
+                        )
+                    );
+                }
+            }
         }
         if let Some(v) = &media.comment {
             println!(
@@ -136,6 +258,9 @@ fn print_media(media: &Media, detailed: bool) {
                     .join("\n")
             );
         }
+        if let Some(MediaDetail::Image(detail)) = &media.detail {
+            print_image_metadata(detail);
+        }
     } else {
         let decorator_style = Style::new().cyan().bright();
         let value_style = Style::new().blue();
@@ -154,7 +279,192 @@ fn print_media(media: &Media, detailed: bool) {
     }
 }
 
-pub fn do_info(opt: Info, _cfg: AppConfig, lib: Library) -> Result<(), Box<dyn Error>> {
+fn print_media_table(media: &[Media]) {
+    let id_width = media
+        .iter()
+        .map(|m| m.id.to_string().len())
+        .max()
+        .unwrap_or(0)
+        .max(2);
+    let kind_width = media
+        .iter()
+        .map(|m| m.kind.to_string().len())
+        .max()
+        .unwrap_or(0)
+        .max(4);
+    println!(
+        "{}",
+        STYLE_FIELD_NAME.apply_to(format!(
+            "{:>id_width$}  {:<kind_width$}  {:>10}  {:<19}  {}",
+            "ID",
+            "Type",
+            "Size",
+            "Add Time",
+            "File Name",
+            id_width = id_width,
+            kind_width = kind_width
+        ))
+    );
+    for m in media {
+        println!(
+            "{:>id_width$}  {:<kind_width$}  {:>10}  {:<19}  {}",
+            STYLE_FIELD_VALUE.apply_to(m.id),
+            m.kind.to_string(),
+            m.filesize.file_size(file_size_opts::CONVENTIONAL).unwrap(),
+            m.time_add.format("%Y-%m-%d %H:%M:%S").to_string(),
+            m.filename,
+            id_width = id_width,
+            kind_width = kind_width
+        );
+    }
+}
+
+// Name of a series or tag, falling back to its UUID if it cannot be read.
+pub fn set_name(lib: &Library, kind: MediaSetType, uuid: &Uuid) -> String {
+    lib.get_set(kind, uuid)
+        .map(|v| v.name)
+        .unwrap_or_else(|_| uuid.to_string())
+}
+
+// Accept either the UUID of an existing set or its name.
+pub fn resolve_set(lib: &Library, kind: MediaSetType, v: &str) -> Result<Uuid, Box<dyn Error>> {
+    let is_series = matches!(kind, MediaSetType::Series);
+    if let Ok(uuid) = Uuid::from_str(v.trim()) {
+        if lib.get_set(kind, &uuid).is_ok() {
+            return Ok(uuid);
+        }
+    }
+    let (series, tag) = lib.get_set_by_name(v.trim().to_string())?;
+    let (uuid, kind_name) = if is_series {
+        (series, "Series")
+    } else {
+        (tag, "Tag")
+    };
+    uuid.ok_or_else(|| format!("{} {} is not existed.", kind_name, v).into())
+}
+
+fn print_media_list(mut media: Vec<Media>, view: &ListView, out: &mut Output) {
+    match view.sort {
+        SortKey::Size => media.sort_by_key(|m| m.filesize),
+        SortKey::Time => media.sort_by_key(|m| m.time_add),
+        // Media without a capture time go after all the others.
+        SortKey::Captured => media.sort_by_cached_key(|m| {
+            let time = capture_time(m);
+            (time.is_none(), time)
+        }),
+        SortKey::Name => media.sort_by(|a, b| a.filename.cmp(&b.filename)),
+        SortKey::Id => media.sort_by_key(|m| m.id),
+    }
+    if view.reverse {
+        media.reverse();
+    }
+    let media: Vec<Media> = media
+        .into_iter()
+        .skip(view.offset)
+        .take(view.limit.unwrap_or(usize::MAX))
+        .collect();
+    if view.table && out.is_text() {
+        print_media_table(&media);
+    } else {
+        for m in media.iter() {
+            out.emit(Record::Media(Box::new(m.into())), || {
+                print_media(m, false, None)
+            });
+        }
+    }
+}
+
+pub fn do_list(
+    opt: List,
+    _cfg: AppConfig,
+    lib: Library,
+    out: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    let series = match &opt.series {
+        Some(v) => Some(resolve_set(&lib, MediaSetType::Series, v)?),
+        None => None,
+    };
+    let tag = match &opt.tag {
+        Some(v) => Some(resolve_set(&lib, MediaSetType::Tag, v)?),
+        None => None,
+    };
+    let mut media = lib
+        .query_media("1 = 1")?
+        .into_iter()
+        .map(|id| lib.get_media(id))
+        .collect::<Result<Vec<Media>, LibError>>()?;
+    // Filters which are not given let every media through.
+    media.retain(|m| {
+        opt.kind.iter().all(|k| &m.kind == k)
+            && series.iter().all(|u| m.series.contains(u))
+            && tag.iter().all(|u| m.tag.contains(u))
+            && opt.since.iter().all(|t| m.time_add >= *t)
+            && opt.until.iter().all(|t| m.time_add <= *t)
+    });
+    print_media_list(media, &opt.view, out);
+    Ok(())
+}
+
+// Join the shell words back into one query, keeping multi-word arguments
+// which were quoted on the command line as a single term.
+pub fn join_query_args(args: &[String]) -> String {
+    args.iter()
+        .map(|v| {
+            if v.contains(char::is_whitespace) && !v.contains('"') {
+                format!("\"{}\"", v)
+            } else {
+                v.clone()
+            }
+        })
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+pub fn do_search(
+    opt: Search,
+    _cfg: AppConfig,
+    lib: Library,
+    out: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    let query_string = join_query_args(&opt.query);
+    let query = Query::parse(&lib, &query_string).map_err(|e| e.in_query(&query_string))?;
+    print_media_list(query.run(&lib)?, &opt.view, out);
+    Ok(())
+}
+
+// Resolve a media by ID, then by hash, and last by file name.
+pub fn find_media(lib: &Library, query_string: &str) -> Vec<Media> {
+    // first try ID
+    if let Ok(v) = query_string.trim().parse() {
+        if let Ok(media) = lib.get_media(v) {
+            return vec![media];
+        }
+    }
+    // then try Hash
+    if query_string.trim().len() == lib.get_hash_size() * 2 {
+        if let Some(query) = Query::hash(query_string.trim()) {
+            if let Some(media) = query.run(lib).unwrap_or_default().pop() {
+                return vec![media];
+            }
+        }
+    }
+    // last try file name
+    match lib.get_media_by_filename(query_string.trim().to_string()) {
+        Ok(v) => v.iter().filter_map(|id| lib.get_media(*id).ok()).collect(),
+        Err(_) => vec![],
+    }
+}
+
+pub fn media_file_path(lib: &Library, media: &Media) -> PathBuf {
+    PathBuf::from(lib.get_path()).join(&media.filepath)
+}
+
+pub fn do_info(
+    opt: Info,
+    _cfg: AppConfig,
+    lib: Library,
+    out: &mut Output,
+) -> Result<(), Box<dyn Error>> {
     let print_library_info = || {
         println!(
             "{}: {}",
@@ -217,110 +527,93 @@ pub fn do_info(opt: Info, _cfg: AppConfig, lib: Library) -> Result<(), Box<dyn E
             )),
         );
     };
-    let get_media = |query_string: String| {
-        // first try ID
-        match query_string.parse() {
-            Ok(v) => {
-                let media = lib.get_media(v);
-                if media.is_ok() {
-                    return (vec![media.unwrap()], query_string);
-                }
-            }
-            Err(_) => (),
-        };
-        // then try Hash
-        if query_string.trim().len() == lib.get_hash_size() * 2 {
-            let ids = lib.query_media(&format!("hash = '{}'", query_string.trim()));
-            if ids.is_ok() {
-                return (
-                    vec![lib.get_media(*ids.unwrap().first().unwrap()).unwrap()],
-                    query_string,
-                );
-            }
-        }
-        // last try file name
-        let media = lib.get_media_by_filename(query_string.trim().to_string());
-        match media {
-            Ok(v) => (
-                v.iter().map(|id| lib.get_media(*id).unwrap()).collect(),
-                query_string,
-            ),
-            Err(_) => (vec![], query_string),
-        }
-    };
-
+    let detailed = opt.detail;
+    let (preview, protocol) = (opt.preview, opt.preview_protocol.clone());
+    let sets_lib = if opt.raw_ids { None } else { Some(&lib) };
     match opt.media {
         Some(v) => {
-            let (media, query_string) = get_media(v);
+            let (media, query_string) = (find_media(&lib, &v), v);
             if media.is_empty() {
-                println!(
-                    "{} {}",
-                    STYLE_ERROR.apply_to("Cannot acquire any media via: "),
-                    STYLE_FIELD_VALUE.apply_to(query_string)
+                out.error(
+                    format!("Cannot acquire any media via: {}", query_string),
+                    || {
+                        println!(
+                            "{} {}",
+                            STYLE_ERROR.apply_to("Cannot acquire any media via: "),
+                            STYLE_FIELD_VALUE.apply_to(&query_string)
+                        )
+                    },
                 )
             } else {
                 for media in media.iter() {
-                    print_media(media, opt.detail);
+                    let origin = find_origin(Path::new(&lib.get_path()), &media.hash);
+                    let mut record = MediaRecord::from(media);
+                    record.origin = origin.clone();
+                    out.emit(Record::Media(Box::new(record)), || {
+                        print_media(media, detailed, sets_lib);
+                        if let (true, Some(origin)) = (detailed, &origin) {
+                            print_origin(origin);
+                        }
+                        if preview && media.kind == MediaType::Image {
+                            if let Err(e) = show_preview(&lib, media, &protocol) {
+                                println!(
+                                    "{}: {}",
+                                    STYLE_ERROR.apply_to("Cannot show the preview"),
+                                    STYLE_FIELD_VALUE.apply_to(e.to_string())
+                                );
+                            }
+                        }
+                    });
                 }
             }
         }
-        None => print_library_info(),
+        None => out.emit(Record::Library((&lib).into()), print_library_info),
     };
     Ok(())
 }
 
+fn display_name(file: &str) -> String {
+    if is_url(file) {
+        file.to_string()
+    } else {
+        PathBuf::from(file)
+            .file_name()
+            .unwrap_or_default()
+            .to_str()
+            .unwrap_or_default()
+            .to_string()
+    }
+}
+
 fn add_one_media(
     lib: &mut Library,
     file: String,
-    kind: Option<MediaType>,
+    prepared: &Prepared,
     title: Option<String>,
     comment: Option<String>,
-) -> Result<u64, LibError> {
-    let kind = kind.clone().unwrap_or_else(|| {
-        if is_url(&file) {
-            return MediaType::URL;
-        }
-        let file = PathBuf::from(&file);
-        let mime_str = tree_magic::from_filepath(file.as_path());
-        let mime_str = mime_str.split("/").collect::<Vec<&str>>();
-        let mime_str = mime_str.first().unwrap();
-        match *mime_str {
-            "image" => MediaType::Image,
-            "audio" => MediaType::Audio,
-            "video" => MediaType::Video,
-            "text" => MediaType::Text,
-            _ => MediaType::Other,
-        }
-    });
-    let id = lib.add_media(file.clone(), kind.clone(), None, None, title, comment)?;
-    println!(
-        "{}: {} {}{}{} {}{}{}",
-        STYLE_FIELD_NAME.apply_to("Successfully Added Media"),
-        STYLE_FIELD_VALUE.apply_to(if is_url(&file) {
-            file
-        } else {
-            let file = PathBuf::from(&file);
-            file.file_name()
-                .unwrap_or_default()
-                .to_str()
-                .unwrap_or_default()
-                .to_string()
-        }),
-        *DECO_LEFT_PAR_M,
-        STYLE_FIELD_VALUE.apply_to(id),
-        *DECO_RIGHT_PAR_M,
-        *DECO_LEFT_PAR_M,
-        STYLE_FIELD_VALUE.apply_to(kind.to_string()),
-        *DECO_RIGHT_PAR_M,
-    );
-    Ok(id)
+) -> Result<(u64, MediaType), Box<dyn Error>> {
+    let kind = prepared.kind.clone();
+    let file = match &prepared.fetched {
+        Some(v) => v.file.to_str().unwrap_or_default().to_string(),
+        None => file,
+    };
+    let hash = prepared.hash.clone();
+    let id = match &prepared.detail {
+        Some(detail) => add_image(lib, file, detail.clone(), hash, title, comment)?,
+        None => add_file(lib, file, kind.clone(), hash, title, comment)?,
+    };
+    if let Err(e) = prepared.record_origin(Path::new(&lib.get_path())) {
+        lib.remove_media(id)?;
+        return Err(e);
+    }
+    Ok((id, kind))
 }
 
 fn parse_input_file(input: &PathBuf) -> Result<Vec<String>, Box<dyn Error>> {
     let lines = std::fs::read_to_string(input)?;
     let lines = lines.lines();
     let lines = lines.map(|v| {
-        if &v[0..7] == "file://" {
+        if v.starts_with("file://") {
             Url::parse(v).unwrap().path().to_string()
         } else if is_url(v) {
             v.to_string()
@@ -330,34 +623,44 @@ fn parse_input_file(input: &PathBuf) -> Result<Vec<String>, Box<dyn Error>> {
     });
     let not_exists: Vec<String> = lines
         .clone()
-        .filter(|v| !is_url(v) && !PathBuf::from(v).is_file())
+        .filter(|v| !is_url(v) && !PathBuf::from(v).exists())
         .collect();
     if !not_exists.is_empty() {
-        Err({ not_exists.join(",") + " are not existed or not a file or url." }.into())
+        Err({ not_exists.join(",") + " are not existed or not a file, directory or url." }.into())
     } else {
         Ok(lines.collect())
     }
 }
 
-pub fn do_add<F: Fn() -> bool>(
-    opt: Add,
-    _cfg: AppConfig,
-    lib: &mut Library,
-    exit_checker: F,
-) -> Result<(), Box<dyn Error>> {
-    let try_remove = |f: &PathBuf| match std::fs::remove_file(f) {
-        Err(e) => println!(
-            "{}: {}",
-            STYLE_ERROR.apply_to("Error when trying remove origin file"),
-            STYLE_FIELD_VALUE.apply_to(e.to_string())
-        ),
-        Ok(_) => (),
-    };
-    let files = if let Some(input) = &opt.input {
+fn plan_import(opt: &Add, lib: &mut Library) -> Result<ImportPlan, Box<dyn Error>> {
+    let inputs = if let Some(input) = &opt.input {
         parse_input_file(&input)?
     } else {
         opt.file.clone()
     };
+    // Directories are expanded in place, remembering the series of each file.
+    let mut files = vec![];
+    let mut dir_series: Vec<Option<String>> = vec![];
+    for f in inputs {
+        let root = PathBuf::from(&f);
+        if is_url(&f) || !root.is_dir() {
+            files.push(f);
+            dir_series.push(None);
+            continue;
+        }
+        if !opt.recursive {
+            return Err(format!("{} is a directory, use --recursive to add it.", f).into());
+        }
+        let walk_opt = WalkOptions {
+            include: &opt.include,
+            exclude: &opt.exclude,
+            hidden: opt.hidden,
+        };
+        for file in walk_dir(&root, &walk_opt)? {
+            dir_series.push(Some(dir_series_name(&root, &file)));
+            files.push(file.to_str().unwrap_or_default().to_string());
+        }
+    }
     let (title, comment) = if files.len() == 1 {
         (opt.title.clone(), opt.comment.clone())
     } else {
@@ -405,109 +708,648 @@ pub fn do_add<F: Fn() -> bool>(
     } else {
         None
     };
+    Ok(ImportPlan {
+        files,
+        dir_series,
+        kind: opt._type.as_ref().map(|v| v.to_string()),
+        title,
+        comment,
+        remove_origin: opt._move,
+        fetch: opt.fetch,
+        series: series.map(|v| v.to_string()),
+        series_per_dir: opt.series_per_dir,
+        sorted: opt.sorted,
+    })
+}
+
+fn try_remove(f: &Path, progress: &ImportProgress, out: &mut Output) {
+    if let Err(e) = std::fs::remove_file(f) {
+        out.error(
+            format!(
+                "Error when trying remove origin file {}: {}",
+                f.display(),
+                e
+            ),
+            || {
+                progress.println(format!(
+                    "{}: {}",
+                    STYLE_ERROR.apply_to("Error when trying remove origin file"),
+                    STYLE_FIELD_VALUE.apply_to(e.to_string())
+                ))
+            },
+        );
+    }
+}
+
+pub fn do_add<F: Fn() -> bool>(
+    opt: Add,
+    _cfg: AppConfig,
+    lib: &mut Library,
+    exit_checker: F,
+    out: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    let mut journal = match &opt.resume {
+        Some(path) => {
+            let journal = Journal::open(path)?;
+            if journal.finished {
+                return Err(format!("Import in {} is already finished.", path.display()).into());
+            }
+            Some(journal)
+        }
+        None => None,
+    };
+    let plan = match &journal {
+        Some(journal) => journal.plan.clone(),
+        None => plan_import(&opt, lib)?,
+    };
+    if journal.is_none() && plan.files.len() > 1 {
+        journal = Some(Journal::create(lib, plan.clone())?);
+    }
+    let (title, comment) = (plan.title.clone(), plan.comment.clone());
 
-    let mut ids: Vec<Option<u64>> = vec![];
-    for f in files.iter() {
-        let id = match add_one_media(
-            lib,
-            f.clone(),
-            opt._type.clone(),
-            title.clone(),
-            comment.clone(),
-        ) {
+    // Files done before an interruption keep the result they had. Their
+    // sources may still be there if it came between journaling and removing.
+    let mut ids: Vec<Option<u64>> = vec![None; plan.files.len()];
+    let mut pending = vec![];
+    let mut leftover = vec![];
+    for (index, f) in plan.files.iter().enumerate() {
+        match journal.as_ref().and_then(|j| j.done.get(&index)) {
+            Some((status, id)) => {
+                ids[index] = *id;
+                if plan.remove_origin
+                    && !matches!(status, ImportStatus::Failed)
+                    && !is_url(f)
+                    && Path::new(f).exists()
+                {
+                    leftover.push(f.clone());
+                }
+            }
+            None => pending.push(index),
+        }
+    }
+    let files: Vec<String> = pending.iter().map(|i| plan.files[*i].clone()).collect();
+    let mut progress = ImportProgress::new(&files, out.is_text() && plan.files.len() > 1);
+    for f in leftover.iter() {
+        try_remove(Path::new(f), &progress, out);
+    }
+    let jobs = opt.jobs.unwrap_or_else(num_cpus::get);
+    let pipeline = Pipeline::start(&files, plan.kind(), lib.get_hash_algo(), plan.fetch, jobs);
+    for (position, (index, (f, prepared))) in pending.into_iter().zip(pipeline).enumerate() {
+        progress.start_file(&f);
+        let added = prepared
+            .map_err(|e| e.into())
+            .and_then(|p| add_one_media(lib, f.clone(), &p, title.clone(), comment.clone()));
+        let (id, status) = match added {
             Err(e) => {
-                if let LibError::AlreadyExists(s) = e {
-                    let id = lib
-                        .query_media(&format!("hash = '{}'", s))
-                        .unwrap_or_else(|v| {
-                            println!(
-                                "{}: {}, {}: {}",
-                                STYLE_ERROR
-                                    .apply_to("Error at querying media via Hash should exists"),
-                                STYLE_FIELD_VALUE.apply_to(s),
-                                STYLE_ERROR.apply_to("Due to"),
-                                STYLE_FIELD_VALUE.apply_to(v.to_string())
+                if let Some(LibError::AlreadyExists(s)) = e.downcast_ref::<LibError>() {
+                    let found = match Query::hash(s) {
+                        Some(query) => query.run(lib),
+                        None => Ok(vec![]),
+                    };
+                    let m = match found {
+                        Ok(mut v) => v.pop(),
+                        Err(v) => {
+                            out.error(
+                                format!(
+                                    "Error at querying media via Hash should exists: {}, Due to: {}",
+                                    s, v
+                                ),
+                                || {
+                                    progress.println(format!(
+                                        "{}: {}, {}: {}",
+                                        STYLE_ERROR.apply_to(
+                                            "Error at querying media via Hash should exists"
+                                        ),
+                                        STYLE_FIELD_VALUE.apply_to(s),
+                                        STYLE_ERROR.apply_to("Due to"),
+                                        STYLE_FIELD_VALUE.apply_to(v.to_string())
+                                    ))
+                                },
                             );
-                            vec![]
-                        })
-                        .first()
-                        .map(|v| *v);
-                    if let Some(id) = id {
-                        let m = lib.get_media(id).unwrap();
-                        println!(
+                            None
+                        }
+                    };
+                    let id = m.as_ref().map(|m| m.id);
+                    out.emit(
+                        Record::Add(AddRecord::Existed {
+                            file: f.clone(),
+                            id,
+                            kind: m.as_ref().map(|m| m.kind.to_string()),
+                        }),
+                        || {
+                            if let Some(m) = &m {
+                                progress.println(format!(
+                                    "{}: {} {}{}{} {}{}{}",
+                                    STYLE_FIELD_NAME.apply_to("Existed Media Found"),
+                                    STYLE_FIELD_VALUE.apply_to(&m.filename),
+                                    *DECO_LEFT_PAR_M,
+                                    STYLE_FIELD_VALUE.apply_to(m.id),
+                                    *DECO_RIGHT_PAR_M,
+                                    *DECO_LEFT_PAR_M,
+                                    STYLE_FIELD_VALUE.apply_to(m.kind.to_string()),
+                                    *DECO_RIGHT_PAR_M,
+                                ));
+                            }
+                        },
+                    );
+                    (id, ImportStatus::Existed)
+                } else {
+                    out.emit(
+                        Record::Add(AddRecord::Failed {
+                            file: f.clone(),
+                            error: e.to_string(),
+                        }),
+                        || {
+                            progress.println(format!(
+                                "{}: {}",
+                                STYLE_ERROR.apply_to("Error when trying add media"),
+                                STYLE_FIELD_VALUE.apply_to(e.to_string())
+                            ))
+                        },
+                    );
+                    (None, ImportStatus::Failed)
+                }
+            }
+            Ok((id, kind)) => {
+                out.emit(
+                    Record::Add(AddRecord::Added {
+                        file: f.clone(),
+                        id,
+                        kind: kind.to_string(),
+                    }),
+                    || {
+                        if progress.is_drawing() {
+                            return;
+                        }
+                        progress.println(format!(
                             "{}: {} {}{}{} {}{}{}",
-                            STYLE_FIELD_NAME.apply_to("Existed Media Found"),
-                            STYLE_FIELD_VALUE.apply_to(m.filename),
+                            STYLE_FIELD_NAME.apply_to("Successfully Added Media"),
+                            STYLE_FIELD_VALUE.apply_to(display_name(&f)),
                             *DECO_LEFT_PAR_M,
                             STYLE_FIELD_VALUE.apply_to(id),
                             *DECO_RIGHT_PAR_M,
                             *DECO_LEFT_PAR_M,
-                            STYLE_FIELD_VALUE.apply_to(m.kind.to_string()),
+                            STYLE_FIELD_VALUE.apply_to(kind.to_string()),
                             *DECO_RIGHT_PAR_M,
-                        );
-                    }
-                    if opt._move && !is_url(&f) {
-                        try_remove(&PathBuf::from(&f));
-                    }
-                    id
-                } else {
+                        ))
+                    },
+                );
+                (Some(id), ImportStatus::Added)
+            }
+        };
+        // Journaled before the source is removed, so a resumed import never
+        // looks for a file which is already in the library.
+        if let Some(journal) = &mut journal {
+            journal.record(index, status, id)?;
+        }
+        if plan.remove_origin && !matches!(status, ImportStatus::Failed) && !is_url(&f) {
+            try_remove(Path::new(&f), &progress, out);
+        }
+        progress.finish_file(position, status);
+        ids[index] = id;
+        if exit_checker() {
+            let summary = progress.finish(true);
+            if out.is_text() {
+                println!("{}", STYLE_ERROR.apply_to(summary));
+                if let Some(journal) = &journal {
                     println!(
                         "{}: {}",
-                        STYLE_ERROR.apply_to("Error when trying add media"),
-                        STYLE_FIELD_VALUE.apply_to(e.to_string())
+                        STYLE_FIELD_NAME.apply_to("Continue it with add --resume"),
+                        STYLE_FIELD_VALUE.apply_to(journal.path().display())
                     );
-                    None
-                }
-            }
-            Ok(id) => {
-                if opt._move {
-                    try_remove(&PathBuf::from(&f));
                 }
-                Some(id)
             }
-        };
-        ids.push(id);
-        if exit_checker() {
             return Ok(());
         }
     }
+    let summary = progress.finish(false);
+    if out.is_text() && plan.files.len() > 1 {
+        println!("{}", STYLE_FIELD_VALUE.apply_to(summary));
+    }
 
-    if opt.sorted && ids.iter().any(|v| v.is_none()) {
-        println!("{}", STYLE_FIELD_VALUE.apply_to("There is some media cannot be added while trying to add it to sorted series. This may break the sort."));
+    if plan.sorted && ids.iter().any(|v| v.is_none()) {
+        if let Some(journal) = &mut journal {
+            journal.finish()?;
+        }
+        let message = "There is some media cannot be added while trying to add it to sorted series. This may break the sort.";
+        out.error(message.to_string(), || {
+            println!("{}", STYLE_FIELD_VALUE.apply_to(message))
+        });
         return Ok(());
     }
 
-    if let Some(uuid) = series {
-        let mut ids = ids;
+    // A resumed import may have assigned some of the series already.
+    if let Some(uuid) = plan.series() {
+        let mut ids = ids.clone();
         ids.retain(|c| c.is_some());
-        dbg!(ids.clone());
-        for (_i, id) in ids.iter().enumerate() {
-            lib.add_to_set(MediaSetType::Series, id.unwrap(), &uuid, None, !opt.sorted)?;
+        let members: HashSet<u64> = lib
+            .get_set(MediaSetType::Series, &uuid)?
+            .media
+            .into_iter()
+            .collect();
+        for id in ids.iter() {
+            if !members.contains(&id.unwrap()) {
+                lib.add_to_set(MediaSetType::Series, id.unwrap(), &uuid, None, !plan.sorted)?;
+            }
         }
-        println!(
-            "Successfully Added {} Medias to Series {}.",
-            ids.len(),
-            uuid
+        out.emit(
+            Record::Membership(MembershipRecord {
+                kind: "series".to_string(),
+                action: "add".to_string(),
+                uuid: uuid.to_string(),
+                media: ids.iter().map(|v| v.unwrap()).collect(),
+            }),
+            || {
+                println!(
+                    "Successfully Added {} Medias to Series {}.",
+                    ids.len(),
+                    uuid
+                )
+            },
         );
     }
+    if plan.series_per_dir {
+        let mut groups: Vec<(String, Vec<u64>)> = vec![];
+        for (id, name) in ids.iter().zip(plan.dir_series.iter()) {
+            if let (Some(id), Some(name)) = (id, name) {
+                match groups.iter_mut().find(|(v, _)| v == name) {
+                    Some((_, v)) => v.push(*id),
+                    None => groups.push((name.clone(), vec![*id])),
+                }
+            }
+        }
+        for (name, ids) in groups {
+            let uuid = match lib.get_set_by_name(name.clone()).unwrap_or((None, None)).0 {
+                Some(uuid) => uuid,
+                None => lib.create_set(MediaSetType::Series, name.clone(), None)?,
+            };
+            let members: HashSet<u64> = lib
+                .get_set(MediaSetType::Series, &uuid)?
+                .media
+                .into_iter()
+                .collect();
+            for id in ids.iter().filter(|v| !members.contains(v)) {
+                lib.add_to_set(MediaSetType::Series, *id, &uuid, None, !plan.sorted)?;
+            }
+            out.emit(
+                Record::Membership(MembershipRecord {
+                    kind: "series".to_string(),
+                    action: "add".to_string(),
+                    uuid: uuid.to_string(),
+                    media: ids.clone(),
+                }),
+                || {
+                    println!(
+                        "Successfully Added {} Medias to Series {}.",
+                        ids.len(),
+                        name
+                    )
+                },
+            );
+        }
+    }
+    if let Some(journal) = &mut journal {
+        journal.finish()?;
+    }
     Ok(())
 }
 
-pub fn do_create(opt: Create, _cfg: AppConfig, lib: &mut Library) -> Result<(), Box<dyn Error>> {
-    let uuid = lib.create_set(MediaSetType::Series, opt.title.clone(), opt.comment)?;
-    if opt.uuid_only {
-        println!("{}", uuid);
-    } else {
-        println!(
-            "{}: {}{}{} {}{}{}",
-            STYLE_FIELD_NAME.apply_to("Successfully created series"),
-            *DECO_LEFT_PAR_M,
-            STYLE_FIELD_NAME.apply_to(opt.title),
-            *DECO_RIGHT_PAR_M,
-            *DECO_LEFT_PAR_M,
-            STYLE_FIELD_NAME.apply_to(uuid),
-            *DECO_RIGHT_PAR_M,
-        );
+pub const TAG_SEPARATOR: char = '/';
+
+// A level of a tag hierarchy: its name, UUID and whether it was created just now.
+pub type TagLevel = (String, Uuid, bool);
+
+// Full names of every level of the tag `name`, from the top: `artist/foo`
+// gives `artist` and `artist/foo`.
+fn tag_levels(name: &str) -> Result<Vec<String>, String> {
+    let parts: Vec<&str> = name.split(TAG_SEPARATOR).map(|v| v.trim()).collect();
+    if parts.iter().any(|v| v.is_empty()) {
+        return Err(format!("{} is not a valid tag name.", name));
+    }
+    Ok((1..=parts.len())
+        .map(|depth| parts[..depth].join(&TAG_SEPARATOR.to_string()))
+        .collect())
+}
+
+// Create the tag `name`, and with `parents` every missing level of its
+// hierarchy. Returns each level with its UUID and whether it was created here.
+pub fn create_tag(
+    lib: &mut Library,
+    name: &str,
+    comment: Option<String>,
+    parents: bool,
+) -> Result<Vec<TagLevel>, Box<dyn Error>> {
+    let levels = tag_levels(name)?;
+    let count = levels.len();
+    let mut result = vec![];
+    for (depth, path) in levels.into_iter().enumerate() {
+        let is_leaf = depth + 1 == count;
+        let existed = lib.get_set_by_name(path.clone()).unwrap_or((None, None)).1;
+        match existed {
+            Some(uuid) if is_leaf && !parents => {
+                return Err(format!("Tag {} is already existed as {}.", path, uuid).into())
+            }
+            Some(uuid) => result.push((path, uuid, false)),
+            None if !is_leaf && !parents => {
+                return Err(format!(
+                    "Parent tag {} is not existed. Use --parents to create it.",
+                    path
+                )
+                .into())
+            }
+            None => {
+                let uuid = lib.create_set(
+                    MediaSetType::Tag,
+                    path.clone(),
+                    if is_leaf { comment.clone() } else { None },
+                )?;
+                result.push((path, uuid, true));
+            }
+        }
+    }
+    Ok(result)
+}
+
+fn do_create_series(
+    opt: Create,
+    lib: &mut Library,
+    out: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    if let Some(uuid) = lib
+        .get_set_by_name(opt.title.clone())
+        .unwrap_or((None, None))
+        .0
+    {
+        return Err(format!("Series {} is already existed as {}.", opt.title, uuid).into());
+    }
+    let uuid = lib.create_set(MediaSetType::Series, opt.title.clone(), opt.comment.clone())?;
+    out.emit(
+        Record::Set(SetRecord::new(
+            MediaSetType::Series,
+            opt.title.clone(),
+            uuid,
+            true,
+        )),
+        || {
+            if opt.uuid_only {
+                println!("{}", uuid);
+            } else {
+                println!(
+                    "{}: {}{}{} {}{}{}",
+                    STYLE_FIELD_NAME.apply_to("Successfully created series"),
+                    *DECO_LEFT_PAR_M,
+                    STYLE_FIELD_NAME.apply_to(&opt.title),
+                    *DECO_RIGHT_PAR_M,
+                    *DECO_LEFT_PAR_M,
+                    STYLE_FIELD_NAME.apply_to(uuid),
+                    *DECO_RIGHT_PAR_M,
+                );
+            }
+        },
+    );
+    Ok(())
+}
+
+fn do_create_tag(opt: Create, lib: &mut Library, out: &mut Output) -> Result<(), Box<dyn Error>> {
+    let tags = create_tag(lib, &opt.title, opt.comment, opt.parents)?;
+    if opt.uuid_only && out.is_text() {
+        println!("{}", tags.last().unwrap().1);
+        return Ok(());
+    }
+    for (name, uuid, created) in tags {
+        let record = SetRecord::new(MediaSetType::Tag, name.clone(), uuid, created);
+        out.emit(Record::Set(record), || {
+            println!(
+                "{}: {}{}{} {}{}{}",
+                STYLE_FIELD_NAME.apply_to(if created {
+                    "Successfully created tag"
+                } else {
+                    "Existed Tag Found"
+                }),
+                *DECO_LEFT_PAR_M,
+                STYLE_FIELD_NAME.apply_to(&name),
+                *DECO_RIGHT_PAR_M,
+                *DECO_LEFT_PAR_M,
+                STYLE_FIELD_NAME.apply_to(uuid),
+                *DECO_RIGHT_PAR_M,
+            )
+        });
+    }
+    Ok(())
+}
+
+pub fn do_create(
+    opt: Create,
+    _cfg: AppConfig,
+    lib: &mut Library,
+    out: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    match opt._type {
+        CreateType::Series => do_create_series(opt, lib, out),
+        CreateType::Tag => do_create_tag(opt, lib, out),
+    }
+}
+
+// Where a media is exported into `dir`: under its file name, or with its ID
+// in front when that is taken. Nothing is ever overwritten.
+fn export_target(dir: &Path, id: u64, filename: &str) -> Result<PathBuf, String> {
+    let mut target = dir.join(filename);
+    if target.exists() {
+        target = dir.join(format!("{}-{}", id, filename));
+    }
+    if target.exists() {
+        return Err(format!(
+            "{} is already existed.",
+            target.to_str().unwrap_or_default()
+        ));
+    }
+    Ok(target)
+}
+
+// Copy the stored file of `media` into `dir`.
+fn export_media_file(lib: &Library, media: &Media, dir: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    std::fs::create_dir_all(dir)?;
+    let target = export_target(dir, media.id, &media.filename)?;
+    std::fs::copy(media_file_path(lib, media), &target)?;
+    Ok(target)
+}
+
+pub fn remove_one_media(
+    lib: &mut Library,
+    media: &Media,
+    keep_file: bool,
+    export: Option<&Path>,
+) -> Result<Option<PathBuf>, Box<dyn Error>> {
+    let has_file = media.kind != MediaType::URL;
+    let exported = match export {
+        Some(dir) if has_file => Some(export_media_file(lib, media, dir)?),
+        _ => None,
+    };
+    for uuid in media.series.iter() {
+        lib.remove_from_set(MediaSetType::Series, media.id, uuid)?;
+    }
+    for uuid in media.tag.iter() {
+        lib.remove_from_set(MediaSetType::Tag, media.id, uuid)?;
+    }
+    let path = media_file_path(lib, media);
+    lib.remove_media(media.id)?;
+    forget_origin(Path::new(&lib.get_path()), &media.hash)?;
+    if has_file && !keep_file {
+        match std::fs::remove_file(&path) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+            _ => (),
+        }
+    }
+    Ok(exported)
+}
+
+pub fn do_remove(
+    opt: Remove,
+    _cfg: AppConfig,
+    lib: &mut Library,
+    out: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    let mut targets: Vec<Media> = vec![];
+    for query_string in opt.media.iter() {
+        let media = find_media(lib, query_string);
+        if media.is_empty() {
+            out.emit(
+                Record::Remove(RemoveRecord::NotFound {
+                    target: query_string.clone(),
+                }),
+                || {
+                    println!(
+                        "{} {}",
+                        STYLE_ERROR.apply_to("Cannot acquire any media via: "),
+                        STYLE_FIELD_VALUE.apply_to(query_string)
+                    )
+                },
+            );
+        }
+        for m in media {
+            if !targets.iter().any(|t| t.id == m.id) {
+                targets.push(m);
+            }
+        }
+    }
+    if targets.is_empty() {
+        return Ok(());
+    }
+
+    if out.is_text() {
+        println!("{}", STYLE_FIELD_NAME.apply_to("Media to be removed"));
+        for m in targets.iter() {
+            print_media(m, false, None);
+        }
+    }
+    if !opt.yes {
+        let theme = ColorfulTheme {
+            values_style: Style::new().yellow().dim(),
+            ..ColorfulTheme::default()
+        };
+        if !Confirm::with_theme(&theme)
+            .default(false)
+            .with_prompt(format!("Remove {} media from the library?", targets.len()))
+            .interact()?
+        {
+            out.error("Removal cancelled by user.".to_string(), || {
+                println!("{}", STYLE_ERROR.apply_to("Removal cancelled."))
+            });
+            return Ok(());
+        }
+    }
+
+    for m in targets.iter() {
+        match remove_one_media(lib, m, opt.keep_file, opt.export.as_deref()) {
+            Ok(exported) => out.emit(
+                Record::Remove(RemoveRecord::Removed {
+                    id: m.id,
+                    filename: m.filename.clone(),
+                    exported: exported
+                        .as_ref()
+                        .map(|v| v.to_str().unwrap_or_default().to_string()),
+                    file_kept: opt.keep_file,
+                }),
+                || {
+                    println!(
+                        "{}: {} {}{}{}",
+                        STYLE_FIELD_NAME.apply_to("Successfully Removed Media"),
+                        STYLE_FIELD_VALUE.apply_to(&m.filename),
+                        *DECO_LEFT_PAR_M,
+                        STYLE_FIELD_VALUE.apply_to(m.id),
+                        *DECO_RIGHT_PAR_M,
+                    );
+                    if let Some(v) = &exported {
+                        println!(
+                            "    {} {}: {}",
+                            *DECO_BRANCH,
+                            STYLE_FIELD_NAME.apply_to("Exported to"),
+                            STYLE_FIELD_VALUE.apply_to(v.to_str().unwrap_or_default())
+                        );
+                    }
+                },
+            ),
+            Err(e) => out.emit(
+                Record::Remove(RemoveRecord::Failed {
+                    id: m.id,
+                    filename: m.filename.clone(),
+                    error: e.to_string(),
+                }),
+                || {
+                    println!(
+                        "{}: {} {}{}{}, {}: {}",
+                        STYLE_ERROR.apply_to("Error when trying remove media"),
+                        STYLE_FIELD_VALUE.apply_to(&m.filename),
+                        *DECO_LEFT_PAR_M,
+                        STYLE_FIELD_VALUE.apply_to(m.id),
+                        *DECO_RIGHT_PAR_M,
+                        STYLE_ERROR.apply_to("Due to"),
+                        STYLE_FIELD_VALUE.apply_to(e.to_string())
+                    )
+                },
+            ),
+        }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn positions() {
+        assert_eq!(series_position(&[4, 9, 2], 9), "2 of 3");
+        assert_eq!(series_position(&[4, 9, 2], 7), "? of 3");
+        assert_eq!(series_position(&[], 7), "? of 0");
+    }
+
+    #[test]
+    fn tag_hierarchy() {
+        assert_eq!(tag_levels("artist").unwrap(), vec!["artist"]);
+        assert_eq!(
+            tag_levels(" artist / foo /bar").unwrap(),
+            vec!["artist", "artist/foo", "artist/foo/bar"]
+        );
+        for name in ["", "artist/", "/foo", "artist//foo", "artist/ /foo"].iter() {
+            assert_eq!(
+                tag_levels(name).unwrap_err(),
+                format!("{} is not a valid tag name.", name)
+            );
+        }
+    }
+
+    #[test]
+    fn export_targets() {
+        let dir = tempfile::tempdir().unwrap();
+        let first = export_target(dir.path(), 7, "a.jpg").unwrap();
+        assert_eq!(first, dir.path().join("a.jpg"));
+        std::fs::write(&first, b"").unwrap();
+        let second = export_target(dir.path(), 7, "a.jpg").unwrap();
+        assert_eq!(second, dir.path().join("7-a.jpg"));
+        std::fs::write(&second, b"").unwrap();
+        assert!(export_target(dir.path(), 7, "a.jpg")
+            .unwrap_err()
+            .ends_with("7-a.jpg is already existed."));
+    }
+}
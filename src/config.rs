@@ -0,0 +1,301 @@
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+use console::style;
+use toml::value::{Table, Value};
+
+use crate::{AppConfig, DEFAULT_PROFILE};
+
+pub const CONFIG_VERSION: u8 = 2;
+
+const CONFIG_KEYS: &[&str] = &["version", "default_library", "libraries"];
+const PROFILE_KEYS: &[&str] = &["library_path", "library_name"];
+
+type Migration = fn(Table) -> Result<Table, String>;
+
+// Step `i` turns a configuration of version `i + 1` into version `i + 2`. New
+// versions only ever append a step here.
+const MIGRATIONS: &[Migration] = &[from_v1];
+
+// Version 1 held a single library, which becomes the default profile.
+fn from_v1(mut config: Table) -> Result<Table, String> {
+    let mut profile = Table::new();
+    for key in PROFILE_KEYS.iter() {
+        match config.remove(*key) {
+            Some(v) => profile.insert(key.to_string(), v),
+            None => return Err(format!("{} is missing.", key)),
+        };
+    }
+    let mut libraries = Table::new();
+    libraries.insert(DEFAULT_PROFILE.to_string(), Value::Table(profile));
+    config.insert(
+        "default_library".to_string(),
+        Value::String(DEFAULT_PROFILE.to_string()),
+    );
+    config.insert("libraries".to_string(), Value::Table(libraries));
+    Ok(config)
+}
+
+// A copy of the file as it was before migrating, never overwriting an older
+// copy.
+fn backup_path(path: &Path, version: i64) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_os_string();
+    let mut n = 0;
+    loop {
+        let mut backup = name.clone();
+        backup.push(format!(".v{}", version));
+        if n > 0 {
+            backup.push(format!(".{}", n));
+        }
+        backup.push(".bak");
+        let backup = path.with_file_name(backup);
+        if !backup.exists() {
+            return backup;
+        }
+        n += 1;
+    }
+}
+
+fn unknown_keys(config: &Table) -> Vec<String> {
+    let mut keys: Vec<String> = config
+        .keys()
+        .filter(|k| !CONFIG_KEYS.contains(&k.as_str()))
+        .cloned()
+        .collect();
+    if let Some(Value::Table(libraries)) = config.get("libraries") {
+        for (name, profile) in libraries.iter() {
+            if let Value::Table(profile) = profile {
+                keys.extend(
+                    profile
+                        .keys()
+                        .filter(|k| !PROFILE_KEYS.contains(&k.as_str()))
+                        .map(|k| format!("libraries.{}.{}", name, k)),
+                );
+            }
+        }
+    }
+    keys
+}
+
+fn warn(message: String) {
+    eprintln!("{}", style(message).yellow());
+}
+
+// Read the configuration file, bringing it up to the current version first.
+// The old file is kept aside when it is migrated. Keys this version does not
+// know are reported and left in the file for the version that wrote them.
+pub fn load_config_file(path: &Path) -> Result<AppConfig, Box<dyn Error>> {
+    let shown = path.to_str().unwrap_or_default();
+    let text = std::fs::read_to_string(path)
+        .map_err(|e| format!("Cannot read configuration file at {} due to {}.", shown, e))?;
+    let mut config: Table = toml::from_str(&text)
+        .map_err(|e| format!("Configuration file at {} is not valid: {}.", shown, e))?;
+    // Files written before the version was kept are version 1.
+    let version = match config.get("version") {
+        None => 1,
+        Some(v) => v.as_integer().ok_or_else(|| {
+            format!(
+                "Version of configuration file at {} is not a number.",
+                shown
+            )
+        })?,
+    };
+    if version < 1 || version > CONFIG_VERSION as i64 {
+        return Err(format!(
+            "Configuration file at {} has version {}, which this version of shiromana-cli does not know.",
+            shown, version
+        )
+        .into());
+    }
+
+    if version < CONFIG_VERSION as i64 {
+        let backup = backup_path(path, version);
+        std::fs::copy(path, &backup)?;
+        for (i, step) in MIGRATIONS.iter().enumerate().skip(version as usize - 1) {
+            config = step(config).map_err(|e| {
+                format!(
+                    "Cannot update configuration file at {} from version {}: {}",
+                    shown,
+                    i + 1,
+                    e
+                )
+            })?;
+            config.insert("version".to_string(), Value::Integer(i as i64 + 2));
+        }
+        // Written as a value, which puts plain keys before the tables.
+        std::fs::write(path, toml::to_string(&Value::Table(config.clone()))?)?;
+        warn(format!(
+            "Configuration file is updated from version {} to {}, the old one is kept at {}.",
+            version,
+            CONFIG_VERSION,
+            backup.to_str().unwrap_or_default()
+        ));
+    }
+
+    for key in unknown_keys(&config) {
+        warn(format!(
+            "Unknown key {} in configuration file at {} is ignored.",
+            key, shown
+        ));
+    }
+    Ok(Value::Table(config)
+        .try_into()
+        .map_err(|e| format!("Configuration file at {} is not valid: {}.", shown, e))?)
+}
+
+// Keys of `from` which `to` does not have, at the top and in the profiles
+// both keep.
+fn carry_unknown_keys(from: Table, to: &mut Table) {
+    for (key, value) in from.into_iter() {
+        match (key.as_str(), value) {
+            ("libraries", Value::Table(libraries)) => {
+                let new = match to.get_mut("libraries") {
+                    Some(Value::Table(v)) => v,
+                    _ => continue,
+                };
+                for (name, profile) in libraries.into_iter() {
+                    if let (Value::Table(old), Some(Value::Table(new))) =
+                        (profile, new.get_mut(&name))
+                    {
+                        for (key, value) in old.into_iter() {
+                            if !PROFILE_KEYS.contains(&key.as_str()) {
+                                new.entry(key).or_insert(value);
+                            }
+                        }
+                    }
+                }
+            }
+            (key, value) if !CONFIG_KEYS.contains(&key) => {
+                to.entry(key.to_string()).or_insert(value);
+            }
+            _ => (),
+        }
+    }
+}
+
+// Write the configuration file, keeping the keys of the old one this version
+// does not know about.
+pub fn store_config(path: &Path, config: &AppConfig) -> Result<(), Box<dyn Error>> {
+    let mut table = match Value::try_from(config)? {
+        Value::Table(v) => v,
+        _ => unreachable!(),
+    };
+    let old = std::fs::read_to_string(path)
+        .ok()
+        .and_then(|v| toml::from_str::<Table>(&v).ok());
+    if let Some(old) = old {
+        carry_unknown_keys(old, &mut table);
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, toml::to_string(&Value::Table(table))?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(text: &str) -> Table {
+        toml::from_str(text).unwrap()
+    }
+
+    #[test]
+    fn v1_becomes_default_profile() {
+        let config = from_v1(table(
+            "library_path = \"/pictures\"\nlibrary_name = \"shiro-lib\"\n",
+        ))
+        .unwrap();
+        assert_eq!(config["default_library"].as_str(), Some(DEFAULT_PROFILE));
+        let profile = &config["libraries"][DEFAULT_PROFILE];
+        assert_eq!(profile["library_path"].as_str(), Some("/pictures"));
+        assert_eq!(profile["library_name"].as_str(), Some("shiro-lib"));
+        assert!(config.get("library_path").is_none());
+
+        let e = from_v1(table("library_path = \"/pictures\"\n")).unwrap_err();
+        assert_eq!(e, "library_name is missing.");
+    }
+
+    #[test]
+    fn reports_unknown_keys() {
+        let config = table(
+            "version = 2\ndefault_library = \"a\"\ntheme = \"dark\"\n\
+             [libraries.a]\nlibrary_path = \"/a\"\nlibrary_name = \"a\"\nreadonly = true\n",
+        );
+        assert_eq!(unknown_keys(&config), vec!["theme", "libraries.a.readonly"]);
+    }
+
+    #[test]
+    fn backups_are_not_overwritten() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        let first = backup_path(&path, 1);
+        assert_eq!(first, dir.path().join("config.toml.v1.bak"));
+        std::fs::write(&first, "").unwrap();
+        assert_eq!(
+            backup_path(&path, 1),
+            dir.path().join("config.toml.v1.1.bak")
+        );
+    }
+
+    #[test]
+    fn migrates_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        let v1 = "library_path = \"/pictures\"\nlibrary_name = \"shiro-lib\"\n";
+        std::fs::write(&path, v1).unwrap();
+        let config = load_config_file(&path).unwrap();
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.default_library, DEFAULT_PROFILE);
+        assert_eq!(config.libraries[DEFAULT_PROFILE].library_name, "shiro-lib");
+        let backup = dir.path().join("config.toml.v1.bak");
+        assert_eq!(std::fs::read_to_string(backup).unwrap(), v1);
+        let written = table(&std::fs::read_to_string(&path).unwrap());
+        assert_eq!(written["version"].as_integer(), Some(CONFIG_VERSION as i64));
+        // Loading it again leaves it as it is.
+        assert!(load_config_file(&path).is_ok());
+        assert!(!dir.path().join("config.toml.v1.1.bak").exists());
+    }
+
+    #[test]
+    fn refuses_bad_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, "version = 3\n").unwrap();
+        assert!(load_config_file(&path)
+            .err()
+            .unwrap()
+            .to_string()
+            .ends_with("has version 3, which this version of shiromana-cli does not know."));
+        std::fs::write(&path, "library_path = \"/pictures\"\n").unwrap();
+        assert!(load_config_file(&path)
+            .err()
+            .unwrap()
+            .to_string()
+            .ends_with("from version 1: library_name is missing."));
+    }
+
+    #[test]
+    fn storing_keeps_unknown_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(
+            &path,
+            "version = 2\ndefault_library = \"a\"\ntheme = \"dark\"\n\
+             [libraries.a]\nlibrary_path = \"/a\"\nlibrary_name = \"a\"\nreadonly = true\n\
+             [libraries.b]\nlibrary_path = \"/b\"\nlibrary_name = \"b\"\nreadonly = true\n",
+        )
+        .unwrap();
+        let mut config = load_config_file(&path).unwrap();
+        config.libraries.remove("b");
+        config.libraries.get_mut("a").unwrap().library_path = "/c".to_string();
+        store_config(&path, &config).unwrap();
+        let written = table(&std::fs::read_to_string(&path).unwrap());
+        assert_eq!(written["theme"].as_str(), Some("dark"));
+        let libraries = written["libraries"].as_table().unwrap();
+        assert_eq!(libraries.len(), 1);
+        assert_eq!(libraries["a"]["library_path"].as_str(), Some("/c"));
+        assert_eq!(libraries["a"]["readonly"].as_bool(), Some(true));
+    }
+}
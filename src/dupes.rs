@@ -0,0 +1,302 @@
+use std::error::Error;
+
+use console::Style;
+use dialoguer::{theme::ColorfulTheme, Confirm, Select};
+use shiromana_rs::library::{Library, MediaSetType};
+use shiromana_rs::media::{Media, MediaDetail, MediaType};
+
+use crate::add_image::probe_image;
+use crate::command::{
+    media_file_path, print_media, remove_one_media, STYLE_ERROR, STYLE_FIELD_NAME,
+    STYLE_FIELD_VALUE,
+};
+use crate::output::{ClusterRecord, Output, Record};
+use crate::phash::{dhash, distance, media_dhash, to_hex, PERCEPTUAL_HASH_KEY};
+use crate::{AppConfig, Dupes, KeepPolicy};
+
+// Images imported before perceptual hashes were kept get theirs from the
+// stored file, and with `save` it is stored for the next run.
+fn ensure_dhash(
+    lib: &mut Library,
+    media: &mut Media,
+    save: bool,
+) -> Result<Option<u64>, Box<dyn Error>> {
+    if let Some(v) = media_dhash(media) {
+        return Ok(Some(v));
+    }
+    let path = media_file_path(lib, media);
+    let detail = match media.detail.take() {
+        Some(MediaDetail::Image(mut detail)) => match image::open(&path) {
+            Ok(image) => {
+                detail
+                    .extra
+                    .insert(PERCEPTUAL_HASH_KEY.to_string(), to_hex(dhash(&image)));
+                detail
+            }
+            Err(_) => {
+                media.detail = Some(MediaDetail::Image(detail));
+                return Ok(None);
+            }
+        },
+        other => match probe_image(&path) {
+            Ok(v) => v,
+            Err(_) => {
+                media.detail = other;
+                return Ok(None);
+            }
+        },
+    };
+    media.detail = Some(MediaDetail::Image(detail));
+    if save {
+        lib.update_media(media)?;
+    }
+    Ok(media_dhash(media))
+}
+
+// Complete linkage: every two images of a group are close enough, so a chain
+// of small differences never puts two different images together. Images are
+// taken in order, each joining the first group it is close to all of.
+fn cluster(hashes: &[u64], threshold: u32) -> Vec<Vec<usize>> {
+    let mut groups: Vec<Vec<usize>> = vec![];
+    for (i, hash) in hashes.iter().enumerate() {
+        let close =
+            |g: &&mut Vec<usize>| g.iter().all(|j| distance(*hash, hashes[*j]) <= threshold);
+        match groups.iter_mut().find(close) {
+            Some(group) => group.push(i),
+            None => groups.push(vec![i]),
+        }
+    }
+    groups.retain(|v| v.len() > 1);
+    groups
+}
+
+fn pixels(media: &Media) -> u64 {
+    match &media.detail {
+        Some(MediaDetail::Image(v)) => v.width as u64 * v.height as u64,
+        _ => 0,
+    }
+}
+
+fn describe(media: &Media) -> String {
+    let size = match &media.detail {
+        Some(MediaDetail::Image(v)) => format!("{}x{}", v.width, v.height),
+        _ => "?x?".to_string(),
+    };
+    format!(
+        "[{}] {} - {}, {} KB",
+        media.id,
+        media.filename,
+        size,
+        media.filesize / 1024
+    )
+}
+
+// Index of the media to keep, `None` to leave the group alone.
+fn pick_survivor(group: &[Media], keep: &KeepPolicy) -> Result<Option<usize>, Box<dyn Error>> {
+    let index = |f: &dyn Fn(&Media, &Media) -> std::cmp::Ordering| {
+        (0..group.len()).max_by(|a, b| f(&group[*a], &group[*b]))
+    };
+    Ok(match keep {
+        KeepPolicy::Largest => index(&|a, b| {
+            (pixels(a), a.filesize)
+                .cmp(&(pixels(b), b.filesize))
+                .then(b.id.cmp(&a.id))
+        }),
+        KeepPolicy::Oldest => index(&|a, b| b.time_add.cmp(&a.time_add)),
+        KeepPolicy::Newest => index(&|a, b| a.time_add.cmp(&b.time_add)),
+        KeepPolicy::Ask => {
+            let theme = ColorfulTheme {
+                values_style: Style::new().yellow().dim(),
+                ..ColorfulTheme::default()
+            };
+            let mut items: Vec<String> = group.iter().map(describe).collect();
+            items.push("Keep all of them".to_string());
+            let choice = Select::with_theme(&theme)
+                .with_prompt("Which one to keep?")
+                .items(&items)
+                .default(0)
+                .interact()?;
+            if choice < group.len() {
+                Some(choice)
+            } else {
+                None
+            }
+        }
+    })
+}
+
+// Tags and series of `other` are given to `survivor` before `other` goes.
+fn merge_into(lib: &mut Library, survivor: u64, other: &Media) -> Result<(), Box<dyn Error>> {
+    let kept = lib.get_media(survivor)?;
+    for uuid in other.tag.iter().filter(|u| !kept.tag.contains(u)) {
+        lib.add_to_set(MediaSetType::Tag, survivor, uuid, None, true)?;
+    }
+    for uuid in other.series.iter().filter(|u| !kept.series.contains(u)) {
+        lib.add_to_set(MediaSetType::Series, survivor, uuid, None, true)?;
+    }
+    Ok(())
+}
+
+pub fn do_dupes(
+    opt: Dupes,
+    _cfg: AppConfig,
+    lib: &mut Library,
+    out: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    if matches!(opt.keep, KeepPolicy::Ask) && !opt.dry_run && !out.is_text() {
+        return Err("Choose a --keep policy other than ask for non-text output.".into());
+    }
+    let mut images = vec![];
+    let mut hashes = vec![];
+    let mut unreadable = 0;
+    for id in lib.query_media("1 = 1")? {
+        let mut media = lib.get_media(id)?;
+        if media.kind != MediaType::Image {
+            continue;
+        }
+        // A dry run leaves the library as it is.
+        match ensure_dhash(lib, &mut media, !opt.dry_run)? {
+            Some(hash) => {
+                hashes.push(hash);
+                images.push(media);
+            }
+            None => unreadable += 1,
+        }
+    }
+    if unreadable > 0 && out.is_text() {
+        println!(
+            "{}",
+            STYLE_ERROR.apply_to(format!(
+                "{} images cannot be read and are skipped.",
+                unreadable
+            ))
+        );
+    }
+
+    let groups: Vec<Vec<Media>> = cluster(&hashes, opt.threshold)
+        .into_iter()
+        .map(|g| g.into_iter().map(|i| images[i].clone()).collect())
+        .collect();
+    if groups.is_empty() {
+        if out.is_text() {
+            println!("{}", STYLE_FIELD_VALUE.apply_to("No similar images found."));
+        }
+        return Ok(());
+    }
+
+    let mut decisions = vec![];
+    for (n, group) in groups.iter().enumerate() {
+        if out.is_text() {
+            println!(
+                "{} {} {}",
+                STYLE_FIELD_NAME.apply_to("Group"),
+                STYLE_FIELD_VALUE.apply_to(n + 1),
+                STYLE_FIELD_NAME.apply_to(format!("({} images)", group.len()))
+            );
+            for m in group.iter() {
+                print_media(m, false, None);
+            }
+        }
+        let survivor = if opt.dry_run {
+            None
+        } else {
+            pick_survivor(group, &opt.keep)?
+        };
+        decisions.push(survivor);
+    }
+    let removing: usize = groups
+        .iter()
+        .zip(decisions.iter())
+        .filter(|(_, d)| d.is_some())
+        .map(|(g, _)| g.len() - 1)
+        .sum();
+    if removing > 0 && !opt.yes && !matches!(opt.keep, KeepPolicy::Ask) {
+        let theme = ColorfulTheme {
+            values_style: Style::new().yellow().dim(),
+            ..ColorfulTheme::default()
+        };
+        if !Confirm::with_theme(&theme)
+            .default(false)
+            .with_prompt(format!("Remove {} media from the library?", removing))
+            .interact()?
+        {
+            out.error("Removal cancelled by user.".to_string(), || {
+                println!("{}", STYLE_ERROR.apply_to("Removal cancelled."))
+            });
+            return Ok(());
+        }
+    }
+
+    for (group, survivor) in groups.iter().zip(decisions.iter()) {
+        let mut removed = vec![];
+        if let Some(survivor) = survivor {
+            let kept = group[*survivor].id;
+            for m in group.iter().filter(|m| m.id != kept) {
+                merge_into(lib, kept, m)?;
+                match remove_one_media(lib, m, opt.keep_file, None) {
+                    Ok(_) => removed.push(m.id),
+                    Err(e) => out.error(
+                        format!("Error when trying remove media {}: {}", m.id, e),
+                        || {
+                            println!(
+                                "{}: {}, {}: {}",
+                                STYLE_ERROR.apply_to("Error when trying remove media"),
+                                STYLE_FIELD_VALUE.apply_to(m.id),
+                                STYLE_ERROR.apply_to("Due to"),
+                                STYLE_FIELD_VALUE.apply_to(e.to_string())
+                            )
+                        },
+                    ),
+                }
+            }
+        }
+        let kept = survivor.map(|i| group[i].id);
+        out.emit(
+            Record::Cluster(ClusterRecord {
+                media: group.iter().map(|m| m.id).collect(),
+                kept,
+                removed: removed.clone(),
+            }),
+            || {
+                if let Some(kept) = kept {
+                    println!(
+                        "{}: {}, {}: {}",
+                        STYLE_FIELD_NAME.apply_to("Kept"),
+                        STYLE_FIELD_VALUE.apply_to(kept),
+                        STYLE_FIELD_NAME.apply_to("Removed"),
+                        STYLE_FIELD_VALUE.apply_to(
+                            removed
+                                .iter()
+                                .map(|v| v.to_string())
+                                .collect::<Vec<_>>()
+                                .join(", ")
+                        )
+                    );
+                }
+            },
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chains_are_not_merged() {
+        // a and c are 8 bits apart, b is 4 from either.
+        let (a, b, c) = (0u64, 0xF, 0xFF);
+        assert_eq!(cluster(&[a, b, c], 4), vec![vec![0, 1]]);
+        assert_eq!(cluster(&[a, b, c], 8), vec![vec![0, 1, 2]]);
+        assert_eq!(cluster(&[b, a, c], 4), vec![vec![0, 1]]);
+    }
+
+    #[test]
+    fn groups() {
+        let hashes = [0u64, u64::MAX, 1, u64::MAX - 1, 0xFFFF_0000];
+        assert_eq!(cluster(&hashes, 2), vec![vec![0, 2], vec![1, 3]]);
+        assert!(cluster(&hashes, 0).is_empty());
+        assert!(cluster(&[], 10).is_empty());
+    }
+}
@@ -0,0 +1,285 @@
+use std::error::Error;
+use std::io::Write;
+use std::process::Command;
+use std::str::FromStr;
+
+use console::Style;
+use dialoguer::{theme::ColorfulTheme, Confirm};
+use serde::{Deserialize, Serialize};
+use shiromana_rs::library::Library;
+use shiromana_rs::media::{Media, MediaType};
+
+use crate::command::{find_media, STYLE_FIELD_NAME, STYLE_FIELD_VALUE};
+use crate::output::{Output, Record};
+use crate::{is_valid_media_type, AppConfig, Edit};
+
+// Unknown names are refused rather than read as Other.
+fn parse_kind(v: &str) -> Result<MediaType, String> {
+    is_valid_media_type(v)
+        .and_then(|_| MediaType::from_str(v.trim()).map_err(|e| e.to_string()))
+        .map_err(|_| format!("{} is not a valid Media Type.", v))
+}
+
+// The part of a media record which can be changed after import.
+#[derive(Serialize, Deserialize, PartialEq, Clone)]
+struct EditableMedia {
+    kind: String,
+    title: Option<String>,
+    comment: Option<String>,
+    sub_kind: Option<String>,
+    kind_addition: Option<String>,
+}
+
+impl EditableMedia {
+    fn from_media(media: &Media) -> Self {
+        Self {
+            kind: media.kind.to_string(),
+            title: media.caption.clone(),
+            comment: media.comment.clone(),
+            sub_kind: media.sub_kind.clone(),
+            kind_addition: media.kind_addition.clone(),
+        }
+    }
+
+    fn apply_to(&self, media: &mut Media) -> Result<(), Box<dyn Error>> {
+        let kind = parse_kind(&self.kind)?;
+        // A detail only describes media of the kind it was worked out for.
+        if kind != media.kind {
+            media.detail = None;
+        }
+        media.kind = kind;
+        media.caption = self.title.clone();
+        media.comment = self.comment.clone();
+        media.sub_kind = self.sub_kind.clone();
+        media.kind_addition = self.kind_addition.clone();
+        Ok(())
+    }
+
+    fn changes(&self, new: &Self) -> Vec<(&'static str, String, String)> {
+        let show = |v: &Option<String>| v.clone().unwrap_or_else(|| "<none>".to_string());
+        let mut changes = vec![];
+        if self.kind != new.kind {
+            changes.push(("Media Type", self.kind.clone(), new.kind.clone()));
+        }
+        for (name, old, new) in [
+            ("Caption", &self.title, &new.title),
+            ("Comment", &self.comment, &new.comment),
+            ("Sub Type", &self.sub_kind, &new.sub_kind),
+            ("Type Addition", &self.kind_addition, &new.kind_addition),
+        ]
+        .iter()
+        {
+            if old != new {
+                changes.push((*name, show(old), show(new)));
+            }
+        }
+        changes
+    }
+}
+
+// An empty string given to a flag clears the field.
+fn apply_flag(field: &mut Option<String>, flag: &Option<String>) {
+    if let Some(v) = flag {
+        *field = if v.is_empty() { None } else { Some(v.clone()) };
+    }
+}
+
+fn render(media: &Media, fields: &EditableMedia) -> Result<String, Box<dyn Error>> {
+    Ok(format!(
+        "# Editing media {} ({}).\n\
+         # Remove a line to clear the field, save and quit to apply.\n\
+         # Media Types: Image, Text, Audio, Video, URL, Other.\n\n{}",
+        media.id,
+        media.filename,
+        toml::to_string(fields)?
+    ))
+}
+
+// The editor may come with arguments, like `code --wait`. It is run without a
+// shell, so nothing in it or in the file name is interpreted.
+fn split_editor(editor: &str) -> Option<(&str, Vec<&str>)> {
+    let mut words = editor.split_whitespace();
+    Some((words.next()?, words.collect()))
+}
+
+fn edit_in_editor(media: &Media, fields: &EditableMedia) -> Result<EditableMedia, Box<dyn Error>> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "vi".to_string());
+    let (program, args) =
+        split_editor(&editor).ok_or_else(|| "The editor to use is empty.".to_string())?;
+    // Created anew with an unpredictable name, and removed when dropped.
+    let mut file = tempfile::Builder::new()
+        .prefix(&format!("shiromana-edit-{}-", media.id))
+        .suffix(".toml")
+        .tempfile()?;
+    file.write_all(render(media, fields)?.as_bytes())?;
+    file.flush()?;
+    let path = file.path();
+    let theme = ColorfulTheme {
+        values_style: Style::new().yellow().dim(),
+        ..ColorfulTheme::default()
+    };
+    loop {
+        let status = Command::new(program).args(&args).arg(path).status()?;
+        if !status.success() {
+            break Err(format!("Editor {} exited with {}.", editor, status).into());
+        }
+        let edited = std::fs::read_to_string(path)?;
+        let parsed = toml::from_str::<EditableMedia>(&edited)
+            .map_err(|e| e.to_string())
+            .and_then(|v| parse_kind(&v.kind).map(|_| v));
+        match parsed {
+            Ok(v) => break Ok(v),
+            Err(e) => {
+                if !Confirm::with_theme(&theme)
+                    .default(true)
+                    .with_prompt(format!("{} Edit again?", e))
+                    .interact()?
+                {
+                    break Err(e.into());
+                }
+            }
+        }
+    }
+}
+
+pub fn do_edit(
+    opt: Edit,
+    _cfg: AppConfig,
+    lib: &mut Library,
+    out: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    let mut media = match find_media(lib, &opt.media).as_slice() {
+        [] => return Err(format!("Cannot acquire any media via: {}", opt.media).into()),
+        [m] => m.clone(),
+        v => {
+            return Err(format!(
+                "{} media are found via {}, use the Media ID instead.",
+                v.len(),
+                opt.media
+            )
+            .into())
+        }
+    };
+    let old = EditableMedia::from_media(&media);
+    let flags_given = opt.title.is_some()
+        || opt.comment.is_some()
+        || opt._type.is_some()
+        || opt.sub_type.is_some()
+        || opt.type_addition.is_some();
+    let new = if flags_given {
+        let mut new = old.clone();
+        if let Some(v) = &opt._type {
+            new.kind = v.to_string();
+        }
+        apply_flag(&mut new.title, &opt.title);
+        apply_flag(&mut new.comment, &opt.comment);
+        apply_flag(&mut new.sub_kind, &opt.sub_type);
+        apply_flag(&mut new.kind_addition, &opt.type_addition);
+        new
+    } else {
+        edit_in_editor(&media, &old)?
+    };
+
+    let changes = old.changes(&new);
+    if !changes.is_empty() {
+        new.apply_to(&mut media)?;
+        lib.update_media(&media)?;
+    }
+    out.emit(Record::Media(Box::new((&media).into())), || {
+        if changes.is_empty() {
+            println!("{}", STYLE_FIELD_VALUE.apply_to("Nothing changed."));
+        }
+        for (name, old, new) in changes.iter() {
+            println!(
+                "{}: {} -> {}",
+                STYLE_FIELD_NAME.apply_to(name),
+                STYLE_FIELD_VALUE.apply_to(old),
+                STYLE_FIELD_VALUE.apply_to(new)
+            );
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fields() -> EditableMedia {
+        EditableMedia {
+            kind: "Image".to_string(),
+            title: Some("Sunset".to_string()),
+            comment: None,
+            sub_kind: None,
+            kind_addition: None,
+        }
+    }
+
+    #[test]
+    fn kinds() {
+        assert!(parse_kind("image").unwrap() == MediaType::Image);
+        assert!(parse_kind(" Other ").unwrap() == MediaType::Other);
+        assert_eq!(
+            parse_kind("picture").unwrap_err(),
+            "picture is not a valid Media Type."
+        );
+    }
+
+    #[test]
+    fn editors() {
+        assert_eq!(split_editor("vim"), Some(("vim", vec![])));
+        assert_eq!(
+            split_editor("  code --wait  -n "),
+            Some(("code", vec!["--wait", "-n"]))
+        );
+        assert_eq!(
+            split_editor("vi; rm -rf ~"),
+            Some(("vi;", vec!["rm", "-rf", "~"]))
+        );
+        assert_eq!(split_editor(" "), None);
+    }
+
+    #[test]
+    fn flags() {
+        let mut field = Some("old".to_string());
+        apply_flag(&mut field, &None);
+        assert_eq!(field.as_deref(), Some("old"));
+        apply_flag(&mut field, &Some("new".to_string()));
+        assert_eq!(field.as_deref(), Some("new"));
+        apply_flag(&mut field, &Some(String::new()));
+        assert_eq!(field, None);
+    }
+
+    #[test]
+    fn changes() {
+        let old = fields();
+        assert!(old.changes(&old).is_empty());
+        let mut new = fields();
+        new.kind = "Other".to_string();
+        new.title = None;
+        new.comment = Some("kept".to_string());
+        assert_eq!(
+            old.changes(&new),
+            vec![
+                ("Media Type", "Image".to_string(), "Other".to_string()),
+                ("Caption", "Sunset".to_string(), "<none>".to_string()),
+                ("Comment", "<none>".to_string(), "kept".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn removed_lines_clear_fields() {
+        let text = toml::to_string(&fields()).unwrap();
+        let edited: String = text
+            .lines()
+            .filter(|l| !l.starts_with("title"))
+            .map(|l| format!("{}\n", l))
+            .collect();
+        let parsed: EditableMedia = toml::from_str(&edited).unwrap();
+        assert_eq!(parsed.kind, "Image");
+        assert_eq!(parsed.title, None);
+    }
+}
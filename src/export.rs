@@ -0,0 +1,430 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use shiromana_rs::library::{Library, MediaSetType};
+use shiromana_rs::media::{Media, MediaType};
+
+use crate::command::{
+    join_query_args, media_file_path, set_name, STYLE_ERROR, STYLE_FIELD_NAME, STYLE_FIELD_VALUE,
+};
+use crate::image_meta::capture_time;
+use crate::output::{ExportRecord, MediaRecord, Output, Record};
+use crate::query::Query;
+use crate::{AppConfig, Collision, Export};
+
+const FIELDS: &[&str] = &[
+    "id", "hash", "filename", "stem", "ext", "kind", "title", "series", "index", "time_add",
+    "captured",
+];
+
+enum Piece {
+    Literal(String),
+    Field { name: String, spec: Option<String> },
+}
+
+// A path template like `{series}/{index:03}-{filename}`. Numbers take a width,
+// zero padded when it starts with 0, and times take a strftime format.
+pub struct Template {
+    pieces: Vec<Piece>,
+}
+
+impl Template {
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut pieces = vec![];
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut field = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(v) => field.push(v),
+                            None => return Err(format!("Unclosed {{ in template {}.", template)),
+                        }
+                    }
+                    let (name, spec) = match field.find(':') {
+                        Some(i) => (field[..i].to_string(), Some(field[i + 1..].to_string())),
+                        None => (field, None),
+                    };
+                    if !FIELDS.contains(&name.as_str()) {
+                        return Err(format!(
+                            "Unknown field {{{}}} in template, available: {}.",
+                            name,
+                            FIELDS.join(", ")
+                        ));
+                    }
+                    // Formats are checked here, as a bad one only shows once a
+                    // value is formatted with it.
+                    match (name.as_str(), spec.as_deref()) {
+                        ("id", Some(spec)) | ("index", Some(spec)) => {
+                            number(0, Some(spec))?;
+                        }
+                        ("time_add", Some(spec)) | ("captured", Some(spec)) => {
+                            check_time_format(spec)?
+                        }
+                        _ => (),
+                    }
+                    if !literal.is_empty() {
+                        pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+                    }
+                    pieces.push(Piece::Field { name, spec });
+                }
+                '}' => return Err(format!("Unmatched }} in template {}.", template)),
+                v => literal.push(v),
+            }
+        }
+        if !literal.is_empty() {
+            pieces.push(Piece::Literal(literal));
+        }
+        Ok(Self { pieces })
+    }
+
+    // Path relative to the export directory. Values never add directories of
+    // their own, only the template and time formats do.
+    fn render(&self, lib: &Library, media: &Media) -> Result<PathBuf, String> {
+        let (stem, ext) = split_filename(&media.filename);
+        let series = media.series.first();
+        let mut s = String::new();
+        for piece in self.pieces.iter() {
+            let (name, spec) = match piece {
+                Piece::Literal(v) => {
+                    s += v;
+                    continue;
+                }
+                Piece::Field { name, spec } => (name.as_str(), spec.as_deref()),
+            };
+            s += &match name {
+                "id" => number(media.id, spec)?,
+                "index" => number(
+                    series
+                        .and_then(|u| lib.get_set(MediaSetType::Series, u).ok())
+                        .and_then(|v| v.media.iter().position(|id| *id == media.id))
+                        .map_or(0, |v| v as u64 + 1),
+                    spec,
+                )?,
+                "time_add" => time(&media.time_add, spec),
+                "captured" => time(&capture_time(media).unwrap_or(media.time_add), spec),
+                other => {
+                    let value = match other {
+                        "hash" => media.hash.clone(),
+                        "filename" => media.filename.clone(),
+                        "stem" => stem.to_string(),
+                        "ext" => ext.to_string(),
+                        "kind" => media.kind.to_string(),
+                        "title" => media.caption.clone().unwrap_or_default(),
+                        _ => series
+                            .map(|u| set_name(lib, MediaSetType::Series, u))
+                            .unwrap_or_default(),
+                    };
+                    sanitize(&value)
+                }
+            };
+        }
+        // Empty values leave empty components, which are dropped.
+        let path: PathBuf = s
+            .split('/')
+            .filter(|v| !v.is_empty() && *v != "." && *v != "..")
+            .collect();
+        if path.as_os_str().is_empty() {
+            return Err(format!(
+                "Template gives an empty path for {}.",
+                media.filename
+            ));
+        }
+        Ok(path)
+    }
+}
+
+fn split_filename(filename: &str) -> (&str, &str) {
+    match filename.rfind('.') {
+        Some(i) if i > 0 => (&filename[..i], &filename[i + 1..]),
+        _ => (filename, ""),
+    }
+}
+
+fn number(v: u64, spec: Option<&str>) -> Result<String, String> {
+    match spec {
+        None => Ok(v.to_string()),
+        Some(spec) => {
+            let width: usize = spec
+                .parse()
+                .map_err(|_| format!("{} is not a width for a number.", spec))?;
+            Ok(if spec.starts_with('0') {
+                format!("{:0width$}", v, width = width)
+            } else {
+                format!("{:width$}", v, width = width)
+            })
+        }
+    }
+}
+
+fn check_time_format(spec: &str) -> Result<(), String> {
+    if StrftimeItems::new(spec).any(|v| v == Item::Error) {
+        return Err(format!("{} is not a format for a time.", spec));
+    }
+    Ok(())
+}
+
+// `spec` is checked by `check_time_format` when the template is parsed.
+fn time(v: &DateTime<Local>, spec: Option<&str>) -> String {
+    v.format(spec.unwrap_or("%Y-%m-%d")).to_string()
+}
+
+fn sanitize(v: &str) -> String {
+    v.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '\0' => '_',
+            c => c,
+        })
+        .collect()
+}
+
+// `photo.jpg` becomes `photo-1.jpg`, `photo-2.jpg` and so on.
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let filename = path
+        .file_name()
+        .and_then(|v| v.to_str())
+        .unwrap_or_default();
+    let (stem, ext) = split_filename(filename);
+    let name = if ext.is_empty() {
+        format!("{}-{}", stem, n)
+    } else {
+        format!("{}-{}.{}", stem, n, ext)
+    };
+    path.with_file_name(name)
+}
+
+fn sidecar_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".json");
+    path.with_file_name(name)
+}
+
+// The media record with names, so the sidecar means something outside the
+// library.
+#[derive(Serialize)]
+struct Sidecar {
+    #[serde(flatten)]
+    media: MediaRecord,
+    series_names: Vec<String>,
+    tag_names: Vec<String>,
+}
+
+fn write_sidecar(lib: &Library, media: &Media, path: &Path) -> Result<(), Box<dyn Error>> {
+    let sidecar = Sidecar {
+        media: media.into(),
+        series_names: media
+            .series
+            .iter()
+            .map(|u| set_name(lib, MediaSetType::Series, u))
+            .collect(),
+        tag_names: media
+            .tag
+            .iter()
+            .map(|u| set_name(lib, MediaSetType::Tag, u))
+            .collect(),
+    };
+    std::fs::write(sidecar_path(path), serde_json::to_string_pretty(&sidecar)?)?;
+    Ok(())
+}
+
+// Hard links cannot cross file systems, those files are copied instead.
+fn export_file(source: &Path, target: &Path, link: bool) -> Result<bool, Box<dyn Error>> {
+    if link && std::fs::hard_link(source, target).is_ok() {
+        return Ok(true);
+    }
+    std::fs::copy(source, target)?;
+    Ok(false)
+}
+
+pub fn do_export(
+    opt: Export,
+    _cfg: AppConfig,
+    lib: &Library,
+    out: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    let template = Template::parse(&opt.template)?;
+    let query_string = join_query_args(&opt.query);
+    let query = Query::parse(lib, &query_string).map_err(|e| e.in_query(&query_string))?;
+    let media: Vec<Media> = query
+        .run(lib)?
+        .into_iter()
+        .filter(|m| m.kind != MediaType::URL)
+        .collect();
+
+    let mut written: HashSet<PathBuf> = HashSet::new();
+    let (mut exported, mut skipped, mut failed) = (0, 0, 0);
+    for m in media.iter() {
+        let result = template.render(lib, m).map_err(|e| e.into()).and_then(
+            |relative| -> Result<Option<(PathBuf, bool)>, Box<dyn Error>> {
+                let mut target = opt.to.join(relative);
+                // Two media of this run never overwrite each other.
+                let taken = |p: &Path| p.exists() || written.contains(p);
+                if taken(&target) {
+                    match opt.collision {
+                        Collision::Skip => return Ok(None),
+                        Collision::Overwrite if !written.contains(&target) => {
+                            std::fs::remove_file(&target)?
+                        }
+                        _ => {
+                            let mut n = 1;
+                            while taken(&numbered(&target, n)) {
+                                n += 1;
+                            }
+                            target = numbered(&target, n);
+                        }
+                    }
+                }
+                std::fs::create_dir_all(target.parent().unwrap())?;
+                let linked = export_file(&media_file_path(lib, m), &target, opt.link)?;
+                if !opt.no_sidecar {
+                    write_sidecar(lib, m, &target)?;
+                }
+                Ok(Some((target, linked)))
+            },
+        );
+        let record = match result {
+            Ok(Some((path, linked))) => {
+                exported += 1;
+                written.insert(path.clone());
+                ExportRecord::Exported {
+                    id: m.id,
+                    path: path.to_str().unwrap_or_default().to_string(),
+                    linked,
+                }
+            }
+            Ok(None) => {
+                skipped += 1;
+                ExportRecord::Skipped { id: m.id }
+            }
+            Err(e) => {
+                failed += 1;
+                ExportRecord::Failed {
+                    id: m.id,
+                    error: e.to_string(),
+                }
+            }
+        };
+        out.emit(Record::Export(record.clone()), || match &record {
+            ExportRecord::Exported { path, .. } => println!(
+                "{} {} {}",
+                STYLE_FIELD_VALUE.apply_to(m.id),
+                STYLE_FIELD_NAME.apply_to("->"),
+                path
+            ),
+            ExportRecord::Skipped { .. } => println!(
+                "{} {}",
+                STYLE_FIELD_VALUE.apply_to(m.id),
+                STYLE_FIELD_NAME.apply_to("skipped, the target is already existed.")
+            ),
+            ExportRecord::Failed { error, .. } => println!(
+                "{} {}",
+                STYLE_FIELD_VALUE.apply_to(m.id),
+                STYLE_ERROR.apply_to(error)
+            ),
+        });
+    }
+    if out.is_text() {
+        println!(
+            "{}",
+            STYLE_FIELD_VALUE.apply_to(format!(
+                "Exported {} media, {} skipped, {} failed.",
+                exported, skipped, failed
+            ))
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDateTime, TimeZone};
+
+    fn parse_error(template: &str) -> String {
+        match Template::parse(template) {
+            Err(e) => e,
+            Ok(_) => panic!("{} is accepted", template),
+        }
+    }
+
+    #[test]
+    fn parses_templates() {
+        let template = Template::parse("{{{series}}}/{index:03}-{filename}").unwrap();
+        let names: Vec<String> = template
+            .pieces
+            .iter()
+            .map(|p| match p {
+                Piece::Literal(v) => format!("'{}'", v),
+                Piece::Field { name, spec } => {
+                    format!("{}:{}", name, spec.as_deref().unwrap_or(""))
+                }
+            })
+            .collect();
+        assert_eq!(
+            names,
+            vec!["'{'", "series:", "'}/'", "index:03", "'-'", "filename:"]
+        );
+        assert!(Template::parse("{kind}/{time_add:%Y/%m}/{hash}.{ext}").is_ok());
+    }
+
+    #[test]
+    fn bad_templates() {
+        assert_eq!(
+            parse_error("{filename"),
+            "Unclosed { in template {filename."
+        );
+        assert_eq!(parse_error("a}"), "Unmatched } in template a}.");
+        assert!(parse_error("{size}").starts_with("Unknown field {size} in template"));
+        assert_eq!(parse_error("{index:x}"), "x is not a width for a number.");
+        assert_eq!(
+            parse_error("{time_add:%Q}"),
+            "%Q is not a format for a time."
+        );
+        assert!(parse_error("{captured:%Y-%}").ends_with("is not a format for a time."));
+    }
+
+    #[test]
+    fn values() {
+        assert_eq!(number(7, None).unwrap(), "7");
+        assert_eq!(number(7, Some("03")).unwrap(), "007");
+        assert_eq!(number(7, Some("3")).unwrap(), "  7");
+        let t = "2021-03-05T10:00:00".parse::<NaiveDateTime>().unwrap();
+        let t = Local.from_local_datetime(&t).unwrap();
+        assert_eq!(time(&t, None), "2021-03-05");
+        assert_eq!(time(&t, Some("%Y/%m")), "2021/03");
+        assert_eq!(sanitize("a/b\\c:d"), "a_b_c_d");
+    }
+
+    #[test]
+    fn file_names() {
+        assert_eq!(split_filename("photo.jpg"), ("photo", "jpg"));
+        assert_eq!(split_filename("a.tar.gz"), ("a.tar", "gz"));
+        assert_eq!(split_filename(".hidden"), (".hidden", ""));
+        assert_eq!(split_filename("README"), ("README", ""));
+        assert_eq!(
+            numbered(Path::new("x/photo.jpg"), 2),
+            Path::new("x/photo-2.jpg")
+        );
+        assert_eq!(numbered(Path::new("x/README"), 1), Path::new("x/README-1"));
+        assert_eq!(
+            sidecar_path(Path::new("x/photo.jpg")),
+            Path::new("x/photo.jpg.json")
+        );
+    }
+}
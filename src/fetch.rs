@@ -0,0 +1,261 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+static FETCH_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+// Downloads larger than this are given up.
+pub const MAX_FETCH_SIZE: u64 = 1 << 30;
+
+// Origins of downloaded media, by media hash, in the root of the library.
+pub const ORIGINS_NAME: &str = "origins.json";
+
+lazy_static! {
+    static ref AGENT: ureq::Agent = ureq::AgentBuilder::new()
+        .timeout_connect(Duration::from_secs(15))
+        .timeout_read(Duration::from_secs(60))
+        .build();
+}
+
+// Where a downloaded media came from. It is kept aside from the media, as
+// only images have a detail to put it in.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Origin {
+    pub url: String,
+    pub fetched_at: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub content_type: Option<String>,
+}
+
+type Origins = HashMap<String, Origin>;
+
+fn load_origins(lib_path: &Path) -> Origins {
+    std::fs::read(lib_path.join(ORIGINS_NAME))
+        .ok()
+        .and_then(|v| serde_json::from_slice(&v).ok())
+        .unwrap_or_default()
+}
+
+fn save_origins(lib_path: &Path, origins: &Origins) -> Result<(), Box<dyn Error>> {
+    std::fs::write(lib_path.join(ORIGINS_NAME), serde_json::to_vec(origins)?)?;
+    Ok(())
+}
+
+pub fn find_origin(lib_path: &Path, hash: &str) -> Option<Origin> {
+    load_origins(lib_path).remove(hash)
+}
+
+pub fn store_origin(lib_path: &Path, hash: &str, origin: &Origin) -> Result<(), Box<dyn Error>> {
+    let mut origins = load_origins(lib_path);
+    origins.insert(hash.to_string(), origin.clone());
+    save_origins(lib_path, &origins)
+}
+
+pub fn forget_origin(lib_path: &Path, hash: &str) -> Result<(), Box<dyn Error>> {
+    let mut origins = load_origins(lib_path);
+    if origins.remove(hash).is_some() {
+        save_origins(lib_path, &origins)?;
+    }
+    Ok(())
+}
+
+// The downloaded file is removed along with this, whether it was imported or
+// the import was given up before getting to it.
+pub struct Fetched {
+    pub file: PathBuf,
+    pub origin: Origin,
+}
+
+impl Drop for Fetched {
+    // Every download has a directory of its own to keep its file name.
+    fn drop(&mut self) {
+        if let Some(dir) = self.file.parent() {
+            std::fs::remove_dir_all(dir).unwrap_or(());
+        }
+    }
+}
+
+fn file_name_of(url: &str) -> String {
+    Url::parse(url)
+        .ok()
+        .and_then(|u| {
+            u.path_segments()
+                .and_then(|mut v| v.rfind(|s| !s.is_empty()).map(|s| s.to_string()))
+        })
+        .map(|v| {
+            v.chars()
+                .map(|c| if c == '/' || c == '\\' { '_' } else { c })
+                .collect()
+        })
+        .unwrap_or_else(|| "download".to_string())
+}
+
+// Download `url` into the temporary directory. Redirects are followed, and
+// the name of the file is taken from the final URL.
+pub fn fetch(url: &str) -> Result<Fetched, Box<dyn Error>> {
+    fetch_at_most(url, MAX_FETCH_SIZE)
+}
+
+fn fetch_at_most(url: &str, limit: u64) -> Result<Fetched, Box<dyn Error>> {
+    let too_large = || format!("{} is larger than {} bytes.", url, limit);
+    let response = match AGENT.get(url).call() {
+        Ok(v) => v,
+        Err(ureq::Error::Status(code, _)) => {
+            return Err(format!("{} responded with HTTP {}.", url, code).into())
+        }
+        Err(e) => return Err(format!("{} cannot be fetched due to {}.", url, e).into()),
+    };
+    let header = |name: &str| response.header(name).map(|v| v.to_string());
+    if let Some(length) = header("Content-Length").and_then(|v| v.parse::<u64>().ok()) {
+        if length > limit {
+            return Err(too_large().into());
+        }
+    }
+    let origin = Origin {
+        url: url.to_string(),
+        fetched_at: Local::now().to_rfc3339(),
+        etag: header("ETag"),
+        last_modified: header("Last-Modified"),
+        content_type: header("Content-Type"),
+    };
+    let dir = std::env::temp_dir().join(format!(
+        "shiromana-fetch-{}-{}",
+        std::process::id(),
+        FETCH_COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+    std::fs::create_dir_all(&dir)?;
+    let fetched = Fetched {
+        file: dir.join(file_name_of(response.get_url())),
+        origin,
+    };
+    // One byte over the limit tells a body of exactly the limit from a larger one.
+    let copied = File::create(&fetched.file)
+        .and_then(|mut f| io::copy(&mut response.into_reader().take(limit + 1), &mut f));
+    match copied {
+        Err(e) => Err(format!("{} cannot be fetched due to {}.", url, e).into()),
+        Ok(v) if v > limit => Err(too_large().into()),
+        Ok(_) => Ok(fetched),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    // Answers a single request with `response` and returns the URL to `path`.
+    fn serve(path: &str, response: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}{}", listener.local_addr().unwrap(), path);
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            stream.write_all(&response).unwrap_or(());
+        });
+        url
+    }
+
+    fn response(status: &str, headers: &str, body: &[u8]) -> Vec<u8> {
+        let mut v = format!(
+            "HTTP/1.1 {}\r\n{}Connection: close\r\n\r\n",
+            status, headers
+        )
+        .into_bytes();
+        v.extend_from_slice(body);
+        v
+    }
+
+    #[test]
+    fn downloads_with_origin() {
+        let url = serve(
+            "/images/cat.png?size=large",
+            response(
+                "200 OK",
+                "Content-Type: image/png\r\nETag: \"abc\"\r\nContent-Length: 5\r\n",
+                b"hello",
+            ),
+        );
+        let fetched = fetch(&url).unwrap();
+        assert_eq!(fetched.file.file_name().unwrap(), "cat.png");
+        assert_eq!(std::fs::read(&fetched.file).unwrap(), b"hello");
+        assert_eq!(fetched.origin.url, url);
+        assert_eq!(fetched.origin.etag.as_deref(), Some("\"abc\""));
+        assert_eq!(fetched.origin.content_type.as_deref(), Some("image/png"));
+        assert_eq!(fetched.origin.last_modified, None);
+
+        let dir = fetched.file.parent().unwrap().to_path_buf();
+        drop(fetched);
+        assert!(!dir.exists());
+    }
+
+    #[test]
+    fn http_errors() {
+        let url = serve(
+            "/gone",
+            response("404 Not Found", "Content-Length: 0\r\n", b""),
+        );
+        let e = fetch(&url).err().unwrap();
+        assert_eq!(e.to_string(), format!("{} responded with HTTP 404.", url));
+    }
+
+    #[test]
+    fn size_is_limited() {
+        let body = vec![b'x'; 64];
+        let url = serve(
+            "/declared",
+            response("200 OK", "Content-Length: 64\r\n", &body),
+        );
+        let e = fetch_at_most(&url, 16).err().unwrap();
+        assert!(e.to_string().ends_with("is larger than 16 bytes."));
+        // Without a length the body is cut off at the limit.
+        let url = serve("/streamed", response("200 OK", "", &body));
+        let e = fetch_at_most(&url, 16).err().unwrap();
+        assert!(e.to_string().ends_with("is larger than 16 bytes."));
+        let url = serve("/exact", response("200 OK", "", &body));
+        assert!(fetch_at_most(&url, 64).is_ok());
+    }
+
+    #[test]
+    fn origins_by_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let origin = Origin {
+            url: "https://example.com/a.txt".to_string(),
+            fetched_at: "2021-03-15T10:00:00+00:00".to_string(),
+            etag: None,
+            last_modified: Some("Mon, 15 Mar 2021 09:00:00 GMT".to_string()),
+            content_type: None,
+        };
+        assert!(find_origin(dir.path(), "abc").is_none());
+        store_origin(dir.path(), "abc", &origin).unwrap();
+        store_origin(dir.path(), "def", &origin).unwrap();
+        let read = find_origin(dir.path(), "abc").unwrap();
+        assert_eq!(read.url, origin.url);
+        assert_eq!(read.last_modified, origin.last_modified);
+        assert_eq!(read.etag, None);
+        forget_origin(dir.path(), "abc").unwrap();
+        assert!(find_origin(dir.path(), "abc").is_none());
+        assert!(find_origin(dir.path(), "def").is_some());
+    }
+
+    #[test]
+    fn file_names() {
+        assert_eq!(file_name_of("https://example.com/a/b.jpg?x=1"), "b.jpg");
+        assert_eq!(file_name_of("https://example.com/a/"), "a");
+        assert_eq!(file_name_of("https://example.com"), "download");
+    }
+}
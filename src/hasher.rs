@@ -0,0 +1,138 @@
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use md5::Md5;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use shiromana_rs::misc::HashAlgo;
+
+const BUFFER_SIZE: usize = 1 << 20;
+
+fn hex<D: Digest>(hasher: D) -> String {
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+// Incremental form of `hash_file`, for content which is read anyway.
+pub enum StreamHasher {
+    Md5(Md5),
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl StreamHasher {
+    pub fn new(algo: &HashAlgo) -> Self {
+        match algo {
+            HashAlgo::MD5 => Self::Md5(Md5::new()),
+            HashAlgo::SHA1 => Self::Sha1(Sha1::new()),
+            HashAlgo::SHA256 => Self::Sha256(Sha256::new()),
+            HashAlgo::Blake3 => Self::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Md5(v) => v.update(data),
+            Self::Sha1(v) => v.update(data),
+            Self::Sha256(v) => v.update(data),
+            Self::Blake3(v) => {
+                v.update(data);
+            }
+        }
+    }
+
+    pub fn finish(self) -> String {
+        match self {
+            Self::Md5(v) => hex(v),
+            Self::Sha1(v) => hex(v),
+            Self::Sha256(v) => hex(v),
+            Self::Blake3(v) => blake3::Hasher::finalize(&v).to_hex().to_string(),
+        }
+    }
+}
+
+// Hashes whatever is read through it.
+pub struct HashingReader<R: Read> {
+    inner: R,
+    hasher: StreamHasher,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub fn new(inner: R, algo: &HashAlgo) -> Self {
+        Self {
+            inner,
+            hasher: StreamHasher::new(algo),
+        }
+    }
+
+    pub fn finish(self) -> String {
+        self.hasher.finish()
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+// Hex digest of the content of `file`, in the same form the library stores.
+pub fn hash_file(file: &Path, algo: &HashAlgo) -> io::Result<String> {
+    let mut f = File::open(file)?;
+    let mut hasher = StreamHasher::new(algo);
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    loop {
+        let read = f.read(&mut buffer)?;
+        if read == 0 {
+            return Ok(hasher.finish());
+        }
+        hasher.update(&buffer[..read]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn digests() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("abc");
+        std::fs::write(&path, b"abc").unwrap();
+        for (algo, digest) in [
+            (HashAlgo::MD5, "900150983cd24fb0d6963f7d28e17f72"),
+            (HashAlgo::SHA1, "a9993e364706816aba3e25717850c26c9cd0d89d"),
+            (
+                HashAlgo::SHA256,
+                "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            ),
+            (
+                HashAlgo::Blake3,
+                "6437b3ac38465133ffb63b75273a8db548c558465d79db03fd359c6cd5bd9d85",
+            ),
+        ]
+        .iter()
+        {
+            assert_eq!(hash_file(&path, algo).unwrap(), *digest);
+        }
+    }
+
+    #[test]
+    fn reading_hashes_the_same() {
+        let mut reader = HashingReader::new(&b"abc"[..], &HashAlgo::SHA256);
+        let mut data = vec![];
+        reader.read_to_end(&mut data).unwrap();
+        assert_eq!(data, b"abc");
+        assert_eq!(
+            reader.finish(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveDateTime, TimeZone};
+use exif::{Exif, In, Tag, Value};
+use shiromana_rs::media::{Media, MediaDetail};
+
+// Metadata is kept in `ImageDetail::extra`, EXIF fields under `exif.`, XMP
+// properties under `xmp.`, and the time the photo was taken, whichever of the
+// two it comes from, under `capture_time` as RFC 3339.
+pub const CAPTURE_TIME_KEY: &str = "capture_time";
+
+const EXIF_TEXT_TAGS: [(Tag, &str); 8] = [
+    (Tag::Make, "Make"),
+    (Tag::Model, "Model"),
+    (Tag::LensModel, "LensModel"),
+    (Tag::Software, "Software"),
+    (Tag::ExposureTime, "ExposureTime"),
+    (Tag::FNumber, "FNumber"),
+    (Tag::FocalLength, "FocalLength"),
+    (Tag::PhotographicSensitivity, "ISO"),
+];
+
+// A packet is seldom over a few kilobytes, anything this large is not one.
+const XMP_MAX_SIZE: usize = 1 << 20;
+const XMP_READ_SIZE: usize = 64 << 10;
+
+const XMP_PROPERTIES: [&str; 6] = [
+    "xmp:Rating",
+    "xmp:Label",
+    "xmp:CreateDate",
+    "xmp:CreatorTool",
+    "photoshop:DateCreated",
+    "dc:title",
+];
+
+fn exif_string(exif: &Exif, tag: Tag) -> Option<String> {
+    let field = exif.get_field(tag, In::PRIMARY)?;
+    let v = match &field.value {
+        Value::Ascii(v) => String::from_utf8_lossy(v.first()?).trim().to_string(),
+        _ => field.display_value().with_unit(exif).to_string(),
+    };
+    if v.is_empty() {
+        None
+    } else {
+        Some(v)
+    }
+}
+
+fn exif_capture_time(exif: &Exif) -> Option<DateTime<FixedOffset>> {
+    let field = exif.get_field(Tag::DateTimeOriginal, In::PRIMARY)?;
+    let mut time = match &field.value {
+        Value::Ascii(v) => exif::DateTime::from_ascii(v.first()?).ok()?,
+        _ => return None,
+    };
+    if let Some(Value::Ascii(v)) = exif
+        .get_field(Tag::OffsetTimeOriginal, In::PRIMARY)
+        .map(|f| &f.value)
+    {
+        if let Some(v) = v.first() {
+            time.parse_offset(v).unwrap_or(());
+        }
+    }
+    let naive = NaiveDate::from_ymd_opt(time.year as i32, time.month as u32, time.day as u32)?
+        .and_hms_opt(time.hour as u32, time.minute as u32, time.second as u32)?;
+    // Without an offset the camera clock is taken as local time.
+    match time.offset {
+        Some(offset) => FixedOffset::east_opt(offset as i32 * 60)?
+            .from_local_datetime(&naive)
+            .single(),
+        None => Local
+            .from_local_datetime(&naive)
+            .earliest()
+            .map(|v| v.with_timezone(v.offset())),
+    }
+}
+
+// Degrees, minutes and seconds into signed decimal degrees.
+fn exif_coordinate(exif: &Exif, tag: Tag, ref_tag: Tag, negative: &str) -> Option<f64> {
+    let dms = match &exif.get_field(tag, In::PRIMARY)?.value {
+        Value::Rational(v) if v.len() >= 3 => {
+            v[0].to_f64() + v[1].to_f64() / 60.0 + v[2].to_f64() / 3600.0
+        }
+        _ => return None,
+    };
+    let sign = match exif_string(exif, ref_tag) {
+        Some(v) if v.eq_ignore_ascii_case(negative) => -1.0,
+        _ => 1.0,
+    };
+    Some(sign * dms)
+}
+
+fn read_exif(file: &Path, extra: &mut HashMap<String, String>) -> Option<DateTime<FixedOffset>> {
+    let mut reader = BufReader::new(File::open(file).ok()?);
+    let exif = exif::Reader::new().read_from_container(&mut reader).ok()?;
+    for (tag, name) in EXIF_TEXT_TAGS.iter() {
+        if let Some(v) = exif_string(&exif, *tag) {
+            extra.insert(format!("exif.{}", name), v);
+        }
+    }
+    if let Some(v) = exif
+        .get_field(Tag::Orientation, In::PRIMARY)
+        .and_then(|f| f.value.get_uint(0))
+    {
+        extra.insert("exif.Orientation".to_string(), v.to_string());
+    }
+    let latitude = exif_coordinate(&exif, Tag::GPSLatitude, Tag::GPSLatitudeRef, "S");
+    let longitude = exif_coordinate(&exif, Tag::GPSLongitude, Tag::GPSLongitudeRef, "W");
+    if let (Some(latitude), Some(longitude)) = (latitude, longitude) {
+        extra.insert("exif.GPSLatitude".to_string(), format!("{:.6}", latitude));
+        extra.insert("exif.GPSLongitude".to_string(), format!("{:.6}", longitude));
+    }
+    let time = exif_capture_time(&exif);
+    if let Some(time) = time {
+        extra.insert("exif.DateTimeOriginal".to_string(), time.to_rfc3339());
+    }
+    time
+}
+
+// XMP is a plain XML packet embedded in the file. Properties are written either
+// as attributes or as elements, so both forms are looked for.
+fn xmp_property(packet: &str, name: &str) -> Option<String> {
+    let attr = format!("{}=\"", name);
+    if let Some(start) = packet.find(&attr).map(|p| p + attr.len()) {
+        let end = packet[start..].find('"')? + start;
+        return Some(packet[start..end].to_string());
+    }
+    let open = format!("<{}>", name);
+    let start = packet.find(&open)? + open.len();
+    let end = packet[start..].find(&format!("</{}>", name))? + start;
+    // Localized values such as dc:title are wrapped in rdf:Alt/rdf:li.
+    let value = packet[start..end]
+        .split('<')
+        .filter_map(|v| v.split('>').nth(1))
+        .map(|v| v.trim())
+        .find(|v| !v.is_empty())
+        .unwrap_or_else(|| packet[start..end].trim());
+    if value.is_empty() {
+        None
+    } else {
+        Some(value.to_string())
+    }
+}
+
+fn parse_xmp_date(v: &str) -> Option<DateTime<FixedOffset>> {
+    DateTime::parse_from_rfc3339(v).ok().or_else(|| {
+        let naive = NaiveDateTime::parse_from_str(v, "%Y-%m-%dT%H:%M:%S").ok()?;
+        Local
+            .from_local_datetime(&naive)
+            .earliest()
+            .map(|v| v.with_timezone(v.offset()))
+    })
+}
+
+// The file is read in chunks until the packet is found, so only the packet is
+// ever held in memory rather than the whole image.
+fn find_xmp_packet<R: Read>(mut reader: R) -> Option<Vec<u8>> {
+    const BEGIN: &[u8] = b"<x:xmpmeta";
+    const END: &[u8] = b"</x:xmpmeta>";
+    let mut buffer = vec![];
+    let mut chunk = vec![0u8; XMP_READ_SIZE];
+    let mut found = false;
+    loop {
+        let read = reader.read(&mut chunk).ok()?;
+        if read == 0 {
+            return None;
+        }
+        buffer.extend_from_slice(&chunk[..read]);
+        if !found {
+            match find_bytes(&buffer, BEGIN) {
+                Some(p) => {
+                    buffer.drain(..p);
+                    found = true;
+                }
+                None => {
+                    // The start of the tag may be cut off at the end.
+                    buffer.drain(..buffer.len().saturating_sub(BEGIN.len() - 1));
+                    continue;
+                }
+            }
+        }
+        if let Some(p) = find_bytes(&buffer, END) {
+            buffer.truncate(p);
+            return Some(buffer);
+        }
+        if buffer.len() > XMP_MAX_SIZE {
+            return None;
+        }
+    }
+}
+
+fn read_xmp(file: &Path, extra: &mut HashMap<String, String>) -> Option<DateTime<FixedOffset>> {
+    let data = find_xmp_packet(File::open(file).ok()?)?;
+    let packet = String::from_utf8_lossy(&data);
+    for name in XMP_PROPERTIES.iter() {
+        if let Some(v) = xmp_property(&packet, name) {
+            extra.insert(format!("xmp.{}", name.split(':').nth(1).unwrap()), v);
+        }
+    }
+    ["xmp:CreateDate", "photoshop:DateCreated"]
+        .iter()
+        .filter_map(|name| xmp_property(&packet, name))
+        .find_map(|v| parse_xmp_date(&v))
+}
+
+fn find_bytes(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+// Neither EXIF nor XMP is required, a file without them gives an empty map.
+pub fn read_metadata(file: &Path) -> HashMap<String, String> {
+    let mut extra = HashMap::new();
+    let exif_time = read_exif(file, &mut extra);
+    let xmp_time = read_xmp(file, &mut extra);
+    if let Some(time) = exif_time.or(xmp_time) {
+        extra.insert(CAPTURE_TIME_KEY.to_string(), time.to_rfc3339());
+    }
+    extra
+}
+
+pub fn capture_time(media: &Media) -> Option<DateTime<Local>> {
+    match &media.detail {
+        Some(MediaDetail::Image(detail)) => detail
+            .extra
+            .get(CAPTURE_TIME_KEY)
+            .and_then(|v| DateTime::parse_from_rfc3339(v).ok())
+            .map(|v| v.with_timezone(&Local)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const PACKET: &str = r#"<x:xmpmeta xmlns:x="adobe:ns:meta/"><rdf:Description xmp:Rating="4" xmp:CreateDate="2021-03-15T10:20:30+09:00"><dc:title><rdf:Alt><rdf:li xml:lang="x-default">Harbour</rdf:li></rdf:Alt></dc:title></rdf:Description></x:xmpmeta>"#;
+
+    fn with_padding(before: usize) -> Vec<u8> {
+        let mut data = vec![0xAAu8; before];
+        data.extend_from_slice(PACKET.as_bytes());
+        data.extend_from_slice(&[0xBB; 100]);
+        data
+    }
+
+    #[test]
+    fn packet_is_found_across_chunks() {
+        for before in [0, 100, XMP_READ_SIZE - 4, XMP_READ_SIZE * 3 - 40].iter() {
+            let packet = find_xmp_packet(Cursor::new(with_padding(*before))).unwrap();
+            let packet = String::from_utf8(packet).unwrap();
+            assert!(packet.starts_with("<x:xmpmeta"));
+            assert!(packet.ends_with("</rdf:Description>"));
+        }
+    }
+
+    #[test]
+    fn missing_or_oversized_packets() {
+        assert!(find_xmp_packet(Cursor::new(vec![0u8; XMP_READ_SIZE * 2])).is_none());
+        let mut data = b"<x:xmpmeta>".to_vec();
+        data.resize(data.len() + XMP_MAX_SIZE + XMP_READ_SIZE, b' ');
+        data.extend_from_slice(b"</x:xmpmeta>");
+        assert!(find_xmp_packet(Cursor::new(data)).is_none());
+    }
+
+    #[test]
+    fn properties() {
+        assert_eq!(xmp_property(PACKET, "xmp:Rating").as_deref(), Some("4"));
+        assert_eq!(xmp_property(PACKET, "dc:title").as_deref(), Some("Harbour"));
+        assert_eq!(xmp_property(PACKET, "xmp:Label"), None);
+    }
+
+    #[test]
+    fn read_from_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("photo.jpg");
+        std::fs::write(&path, with_padding(XMP_READ_SIZE + 7)).unwrap();
+        let mut extra = HashMap::new();
+        let time = read_xmp(&path, &mut extra).unwrap();
+        assert_eq!(time.to_rfc3339(), "2021-03-15T10:20:30+09:00");
+        assert_eq!(extra["xmp.Rating"], "4");
+        assert_eq!(extra["xmp.title"], "Harbour");
+    }
+}
@@ -0,0 +1,440 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use chrono::Local;
+use indicatif::{ProgressBar, ProgressStyle};
+use shiromana_rs::library::Library;
+
+use crate::backup::{
+    library_files, partial_path, to_archive_path, written_files, Manifest, ManifestFile,
+    MANIFEST_NAME,
+};
+use crate::command::{STYLE_ERROR, STYLE_FIELD_NAME, STYLE_FIELD_VALUE};
+use crate::hasher::{hash_file, HashingReader};
+use crate::output::{IncrementalRecord, Output, Record, VerifyRecord};
+use crate::Backup;
+
+// Layout of the backup directory: media content is stored once under its
+// hash in `objects`, every run adds a directory under `generations` with the
+// other files of the library and a manifest of all of them.
+const OBJECTS_DIR_NAME: &str = "objects";
+const GENERATIONS_DIR_NAME: &str = "generations";
+
+fn object_path(target: &Path, hash: &str) -> PathBuf {
+    let hash = hash.to_ascii_lowercase();
+    target
+        .join(OBJECTS_DIR_NAME)
+        .join(&hash[..2.min(hash.len())])
+        .join(&hash)
+}
+
+// Finished generations, oldest first. Their names sort by time.
+fn generations(target: &Path) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = std::fs::read_dir(target.join(GENERATIONS_DIR_NAME))
+        .into_iter()
+        .flatten()
+        .filter_map(|d| d.ok().map(|d| d.path()))
+        .filter(|p| p.extension() != Some("partial".as_ref()))
+        .filter(|p| p.join(MANIFEST_NAME).is_file())
+        .collect();
+    dirs.sort();
+    dirs
+}
+
+fn read_manifest(generation: &Path) -> Result<Manifest, Box<dyn Error>> {
+    let data = std::fs::read_to_string(generation.join(MANIFEST_NAME))?;
+    Ok(serde_json::from_str(&data)?)
+}
+
+// Copy `source` to `target` and give back the hash of what was copied.
+fn copy_hashed(
+    source: &Path,
+    target: &Path,
+    lib: &Library,
+    bar: &ProgressBar,
+) -> Result<String, Box<dyn Error>> {
+    std::fs::create_dir_all(target.parent().unwrap())?;
+    let mut reader = HashingReader::new(
+        bar.wrap_read(BufReader::new(File::open(source)?)),
+        &lib.get_hash_algo(),
+    );
+    std::io::copy(&mut reader, &mut BufWriter::new(File::create(target)?))?;
+    Ok(reader.finish())
+}
+
+struct Generation {
+    manifest: Manifest,
+    copied: usize,
+    copied_size: u64,
+}
+
+fn new_generation<F: Fn() -> bool>(
+    lib: &Library,
+    files: &[PathBuf],
+    media: &HashMap<String, String>,
+    target: &Path,
+    generation: &Path,
+    exit_checker: F,
+    out: &mut Output,
+) -> Result<Option<Generation>, Box<dyn Error>> {
+    let lib_path = PathBuf::from(lib.get_path());
+    let mut manifest = Manifest::new(lib);
+    let mut pending = vec![];
+    let mut total = 0;
+    for file in files.iter() {
+        let size = std::fs::metadata(lib_path.join(file))?.len();
+        let stored = matches!(
+            media.get(file.to_str().unwrap_or_default()),
+            Some(hash) if object_path(target, hash).exists()
+        );
+        if !stored {
+            total += size;
+        }
+        pending.push((file, size, stored));
+    }
+    let bar = if out.is_text() && console::user_attended() {
+        ProgressBar::new(total)
+    } else {
+        ProgressBar::hidden()
+    };
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} at {bytes_per_sec}, ETA {eta} {wide_msg}")
+            .progress_chars("#>-"),
+    );
+
+    let (mut copied, mut copied_size) = (0, 0);
+    for (file, size, stored) in pending.into_iter() {
+        let source = lib_path.join(file);
+        let name = to_archive_path(file);
+        let relative = file.to_str().unwrap_or_default();
+        bar.set_message(name.clone());
+        let (hash, object) = match media.get(relative) {
+            Some(hash) if stored => (hash.to_ascii_lowercase(), Some(hash.to_ascii_lowercase())),
+            Some(hash) => {
+                let object = object_path(target, hash);
+                let partial = partial_path(&object);
+                let copied_hash = copy_hashed(&source, &partial, lib, &bar)?;
+                copied += 1;
+                copied_size += size;
+                if copied_hash.eq_ignore_ascii_case(hash) {
+                    std::fs::rename(&partial, &object)?;
+                    (copied_hash.clone(), Some(copied_hash))
+                } else {
+                    // Not what the library says it is, so it cannot go in the
+                    // store under that name, keep it with this run instead.
+                    std::fs::remove_file(&partial).unwrap_or(());
+                    let message = format!(
+                        "{} does not match its hash in the library, run verify to check it.",
+                        relative
+                    );
+                    out.error(message.clone(), || {
+                        bar.println(format!("{}", STYLE_ERROR.apply_to(message)))
+                    });
+                    (
+                        copy_hashed(&source, &generation.join(file), lib, &bar)?,
+                        None,
+                    )
+                }
+            }
+            None => {
+                copied += 1;
+                copied_size += size;
+                (
+                    copy_hashed(&source, &generation.join(file), lib, &bar)?,
+                    None,
+                )
+            }
+        };
+        manifest.files.push(ManifestFile {
+            path: name,
+            size,
+            hash,
+            object,
+        });
+        if exit_checker() {
+            bar.abandon_with_message("Interrupted.");
+            return Ok(None);
+        }
+    }
+    bar.finish_and_clear();
+    std::fs::write(
+        generation.join(MANIFEST_NAME),
+        serde_json::to_string_pretty(&manifest)?,
+    )?;
+    Ok(Some(Generation {
+        manifest,
+        copied,
+        copied_size,
+    }))
+}
+
+// Drop all but the newest `keep` generations, then the objects none of the
+// rest refers to.
+fn prune(target: &Path, keep: usize) -> Result<Vec<String>, Box<dyn Error>> {
+    let all = generations(target);
+    let mut pruned = vec![];
+    for dir in all.iter().take(all.len().saturating_sub(keep)) {
+        std::fs::remove_dir_all(dir)?;
+        pruned.push(
+            dir.file_name()
+                .and_then(|v| v.to_str())
+                .unwrap_or_default()
+                .to_string(),
+        );
+    }
+    if pruned.is_empty() {
+        return Ok(pruned);
+    }
+    let mut used = HashSet::new();
+    for dir in generations(target) {
+        for file in read_manifest(&dir)?.files {
+            if let Some(v) = file.object {
+                used.insert(v);
+            }
+        }
+    }
+    let objects = std::fs::read_dir(target.join(OBJECTS_DIR_NAME))
+        .into_iter()
+        .flatten()
+        .filter_map(|d| d.ok())
+        .filter_map(|d| std::fs::read_dir(d.path()).ok())
+        .flatten()
+        .filter_map(|f| f.ok().map(|f| f.path()));
+    for object in objects {
+        let name = object
+            .file_name()
+            .and_then(|v| v.to_str())
+            .unwrap_or_default();
+        if !used.contains(name) {
+            std::fs::remove_file(&object)?;
+        }
+    }
+    Ok(pruned)
+}
+
+// Check the backup directory against the library: the newest generation has
+// to hold every media of the library, and every file of every generation has
+// to match its manifest.
+fn verify_target(lib: &Library, target: &Path, out: &mut Output) -> Result<usize, Box<dyn Error>> {
+    let all = generations(target);
+    let newest = all
+        .last()
+        .ok_or("There is no backup in the directory yet.")?;
+    let mut problems = 0;
+    let mut report = |problem: &str, path: String, detail: Option<String>| {
+        problems += 1;
+        out.emit(
+            Record::Verify(VerifyRecord {
+                problem: problem.to_string(),
+                id: None,
+                path: path.clone(),
+                detail: detail.clone(),
+                fix: None,
+            }),
+            || {
+                println!(
+                    "{} {}{}",
+                    STYLE_ERROR.apply_to(format!("{:<9}", problem)),
+                    STYLE_FIELD_VALUE.apply_to(path),
+                    detail.map(|v| format!(", {}", v)).unwrap_or_default()
+                )
+            },
+        );
+    };
+
+    let newest_manifest = read_manifest(newest)?;
+    let backed_up: HashSet<&str> = newest_manifest
+        .files
+        .iter()
+        .map(|f| f.path.as_str())
+        .collect();
+    for id in lib.query_media("1 = 1")? {
+        let m = lib.get_media(id)?;
+        let name = to_archive_path(Path::new(&m.filepath));
+        if !backed_up.contains(name.as_str())
+            && Path::new(&lib.get_path()).join(&m.filepath).exists()
+        {
+            report(
+                "missing",
+                name,
+                Some("not in the newest backup".to_string()),
+            );
+        }
+    }
+    let mut checked = HashSet::new();
+    for dir in all.iter() {
+        let manifest = read_manifest(dir)?;
+        let algo = manifest.algo()?;
+        for file in manifest.files.iter() {
+            let path = match &file.object {
+                Some(v) => object_path(target, v),
+                None => dir.join(file.path.split_once('/').map_or("", |v| v.1)),
+            };
+            if !checked.insert(path.clone()) {
+                continue;
+            }
+            let shown = path
+                .strip_prefix(target)
+                .unwrap_or(&path)
+                .to_str()
+                .unwrap_or_default()
+                .to_string();
+            if !path.exists() {
+                report("missing", shown, None);
+            } else if !hash_file(&path, &algo)?.eq_ignore_ascii_case(&file.hash) {
+                report("corrupted", shown, None);
+            }
+        }
+    }
+    Ok(problems)
+}
+
+pub fn do_incremental_backup<F: Fn() -> bool>(
+    opt: &Backup,
+    target: &Path,
+    lib: &Library,
+    exit_checker: F,
+    out: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    if opt.verify {
+        let problems = verify_target(lib, target, out)?;
+        if out.is_text() {
+            println!(
+                "{}",
+                STYLE_FIELD_VALUE.apply_to(format!(
+                    "Checked {} generations, {} problems found.",
+                    generations(target).len(),
+                    problems
+                ))
+            );
+        }
+        if problems > 0 {
+            out.finish();
+            exit(1);
+        }
+        return Ok(());
+    }
+
+    let lib_path = PathBuf::from(lib.get_path());
+    let files = library_files(&lib_path)?;
+    let mut media = HashMap::new();
+    for id in lib.query_media("1 = 1")? {
+        let m = lib.get_media(id)?;
+        media.insert(m.filepath, m.hash);
+    }
+    let media_paths: Vec<String> = media.keys().cloned().collect();
+    let before = written_files(&lib_path, &files, &media_paths);
+
+    let mut name = Local::now().format("%Y%m%d-%H%M%S").to_string();
+    let generations_dir = target.join(GENERATIONS_DIR_NAME);
+    if generations_dir.join(&name).exists() {
+        name = format!("{}-{}", name, std::process::id());
+    }
+    let generation = generations_dir.join(&name);
+    // Written aside first, so an interrupted run is never taken for a backup.
+    let partial = partial_path(&generation);
+    std::fs::create_dir_all(&partial)?;
+    let result = new_generation(lib, &files, &media, target, &partial, exit_checker, out);
+    let Generation {
+        manifest,
+        copied,
+        copied_size,
+    } = match result {
+        Ok(Some(v)) => v,
+        Ok(None) => {
+            std::fs::remove_dir_all(&partial).unwrap_or(());
+            return Ok(());
+        }
+        Err(e) => {
+            std::fs::remove_dir_all(&partial).unwrap_or(());
+            return Err(e);
+        }
+    };
+    if written_files(&lib_path, &files, &media_paths) != before {
+        std::fs::remove_dir_all(&partial).unwrap_or(());
+        return Err(
+            "The library was written to during the backup, try again when it is not in use.".into(),
+        );
+    }
+    std::fs::rename(&partial, &generation)?;
+    let pruned = match opt.keep {
+        Some(keep) => prune(target, keep.max(1))?,
+        None => vec![],
+    };
+
+    out.emit(
+        Record::Incremental(IncrementalRecord {
+            target: target.to_str().unwrap_or_default().to_string(),
+            generation: name.clone(),
+            library_uuid: manifest.library_uuid.clone(),
+            files: manifest.files.len(),
+            copied,
+            copied_size,
+            pruned: pruned.clone(),
+        }),
+        || {
+            println!(
+                "{} {} {}",
+                STYLE_FIELD_NAME.apply_to("Backed up"),
+                STYLE_FIELD_VALUE.apply_to(format!(
+                    "{} files, {} of them copied",
+                    manifest.files.len(),
+                    copied
+                )),
+                STYLE_FIELD_NAME.apply_to(format!("as generation {}", name))
+            );
+            if !pruned.is_empty() {
+                println!(
+                    "{} {}",
+                    STYLE_FIELD_NAME.apply_to("Pruned"),
+                    STYLE_FIELD_VALUE.apply_to(pruned.join(", "))
+                );
+            }
+        },
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn objects_by_hash() {
+        let target = Path::new("backup");
+        assert_eq!(
+            object_path(target, "ABCDEF"),
+            Path::new("backup/objects/ab/abcdef")
+        );
+        assert_eq!(object_path(target, "a"), Path::new("backup/objects/a/a"));
+    }
+
+    #[test]
+    fn finished_generations() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join(GENERATIONS_DIR_NAME);
+        assert!(generations(dir.path()).is_empty());
+        for (name, finished) in [
+            ("20210302-100000", true),
+            ("20210301-100000", true),
+            ("20210303-100000.partial", true),
+            ("20210304-100000", false),
+        ]
+        .iter()
+        {
+            std::fs::create_dir_all(root.join(name)).unwrap();
+            if *finished {
+                std::fs::write(root.join(name).join(MANIFEST_NAME), b"{}").unwrap();
+            }
+        }
+        assert_eq!(
+            generations(dir.path()),
+            vec![root.join("20210301-100000"), root.join("20210302-100000")]
+        );
+    }
+}
@@ -0,0 +1,203 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use shiromana_rs::library::Library;
+use shiromana_rs::media::MediaType;
+use shiromana_rs::misc::Uuid;
+
+use crate::progress::ImportStatus;
+
+pub const JOURNAL_DIR_NAME: &str = "imports";
+
+// Everything `add` decided before the first file was imported, so an
+// interrupted import can be carried on without the original arguments.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ImportPlan {
+    pub files: Vec<String>,
+    pub dir_series: Vec<Option<String>>,
+    pub kind: Option<String>,
+    pub title: Option<String>,
+    pub comment: Option<String>,
+    pub remove_origin: bool,
+    #[serde(default)]
+    pub fetch: bool,
+    pub series: Option<String>,
+    pub series_per_dir: bool,
+    pub sorted: bool,
+}
+
+impl ImportPlan {
+    pub fn kind(&self) -> Option<MediaType> {
+        self.kind.as_ref().and_then(|v| MediaType::from_str(v).ok())
+    }
+
+    pub fn series(&self) -> Option<Uuid> {
+        self.series.as_ref().and_then(|v| Uuid::parse_str(v).ok())
+    }
+}
+
+// The journal is a file of JSON lines: the plan first, then one line for every
+// file as soon as it is done, and a last line once series are assigned.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "entry", rename_all = "snake_case")]
+enum Entry {
+    Plan(ImportPlan),
+    Item {
+        index: usize,
+        status: ImportStatus,
+        id: Option<u64>,
+    },
+    Finished,
+}
+
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+    pub plan: ImportPlan,
+    pub done: BTreeMap<usize, (ImportStatus, Option<u64>)>,
+    pub finished: bool,
+}
+
+impl Journal {
+    pub fn create(lib: &Library, plan: ImportPlan) -> io::Result<Self> {
+        let dir = PathBuf::from(lib.get_path()).join(JOURNAL_DIR_NAME);
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join(format!(
+            "import-{}.journal",
+            Local::now().format("%Y%m%d-%H%M%S%.3f")
+        ));
+        let file = OpenOptions::new()
+            .create_new(true)
+            .append(true)
+            .open(&path)?;
+        let mut journal = Self {
+            path,
+            file,
+            plan: plan.clone(),
+            done: BTreeMap::new(),
+            finished: false,
+        };
+        journal.write(&Entry::Plan(plan))?;
+        Ok(journal)
+    }
+
+    pub fn open(path: &Path) -> Result<Self, Box<dyn Error>> {
+        let content = std::fs::read_to_string(path)?;
+        let mut lines = content.lines();
+        let plan = match lines.next().map(serde_json::from_str::<Entry>) {
+            Some(Ok(Entry::Plan(plan))) => plan,
+            _ => return Err(format!("{} is not an import journal.", path.display()).into()),
+        };
+        let mut done = BTreeMap::new();
+        let mut finished = false;
+        // The last line is cut short if the import was killed while writing it.
+        for entry in lines.filter_map(|v| serde_json::from_str::<Entry>(v).ok()) {
+            match entry {
+                Entry::Item { index, status, id } => {
+                    done.insert(index, (status, id));
+                }
+                Entry::Finished => finished = true,
+                Entry::Plan(_) => (),
+            }
+        }
+        let mut file = OpenOptions::new().append(true).open(path)?;
+        if !content.ends_with('\n') {
+            file.write_all(b"\n")?;
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            plan,
+            done,
+            finished,
+        })
+    }
+
+    fn write(&mut self, entry: &Entry) -> io::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.flush()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn record(
+        &mut self,
+        index: usize,
+        status: ImportStatus,
+        id: Option<u64>,
+    ) -> io::Result<()> {
+        self.done.insert(index, (status, id));
+        self.write(&Entry::Item { index, status, id })
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        self.finished = true;
+        self.write(&Entry::Finished)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn plan() -> ImportPlan {
+        ImportPlan {
+            files: vec![
+                "a.png".to_string(),
+                "b.png".to_string(),
+                "c.png".to_string(),
+            ],
+            dir_series: vec![None, None, None],
+            kind: Some("Image".to_string()),
+            title: None,
+            comment: None,
+            remove_origin: true,
+            fetch: false,
+            series: Some(Uuid::nil().to_string()),
+            series_per_dir: false,
+            sorted: false,
+        }
+    }
+
+    #[test]
+    fn reopen_after_interruption() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("import.journal");
+        let mut content = serde_json::to_string(&Entry::Plan(plan())).unwrap();
+        content += "\n{\"entry\":\"item\",\"index\":0,\"status\":\"added\",\"id\":7}\n";
+        // Killed while writing the next line.
+        content += "{\"entry\":\"item\",\"ind";
+        std::fs::write(&path, content).unwrap();
+
+        let mut journal = Journal::open(&path).unwrap();
+        assert_eq!(journal.plan.files.len(), 3);
+        assert!(journal.plan.series() == Some(Uuid::nil()));
+        assert!(!journal.finished);
+        assert_eq!(journal.done.len(), 1);
+        assert!(matches!(journal.done[&0], (ImportStatus::Added, Some(7))));
+        journal.record(1, ImportStatus::Failed, None).unwrap();
+        journal.finish().unwrap();
+
+        let journal = Journal::open(&path).unwrap();
+        assert!(journal.finished);
+        assert!(matches!(journal.done[&1], (ImportStatus::Failed, None)));
+    }
+
+    #[test]
+    fn other_files_are_refused() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("list.txt");
+        std::fs::write(&path, "a.png\nb.png\n").unwrap();
+        assert!(Journal::open(&path).is_err());
+    }
+}
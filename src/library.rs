@@ -3,30 +3,157 @@ use std::{boxed::Box, error::Error, fs, path};
 use console::style;
 use shiromana_rs::library::Library;
 
-use crate::AppConfig;
+use crate::command::{DECO_LEFT_PAR_M, DECO_RIGHT_PAR_M, STYLE_FIELD_NAME, STYLE_FIELD_VALUE};
+use crate::config::store_config;
+use crate::output::{Output, ProfileRecord, Record};
+use crate::{AppConfig, LibraryAdd, LibraryCmd, LibraryCommand, LibraryProfile};
 
-pub fn create_library(config: &AppConfig) -> Result<Library, Box<dyn Error>> {
-    println!(
+pub fn create_library(profile: &LibraryProfile) -> Result<Library, Box<dyn Error>> {
+    // On stderr, as stdout may be carrying records.
+    eprintln!(
         "{}",
         style("I am now creating Path and Library for you.")
             .blue()
             .bright()
     );
-    dbg!(&config);
-    fs::create_dir_all(path::Path::new(&config.library_path))?;
+    fs::create_dir_all(path::Path::new(&profile.library_path))?;
     Ok(Library::create(
-        config.library_path.clone(),
-        config.library_name.clone(),
+        profile.library_path.clone(),
+        profile.library_name.clone(),
         None,
         None,
     )?)
 }
 
-pub fn open_library(config: &AppConfig) -> Result<Library, Box<dyn Error>> {
+pub fn open_library(profile: &LibraryProfile) -> Result<Library, Box<dyn Error>> {
     Ok(Library::open(
-        config.library_path.clone()
+        profile.library_path.clone()
             + if cfg!(target = "windows") { "\\" } else { "/" }
-            + &config.library_name
+            + &profile.library_name
             + ".mlib",
     )?)
 }
+
+fn profile_record(config: &AppConfig, name: &str, profile: &LibraryProfile) -> Record {
+    Record::Profile(ProfileRecord {
+        name: name.to_string(),
+        path: profile.library_path.clone(),
+        library_name: profile.library_name.clone(),
+        default: config.default_library == name,
+    })
+}
+
+fn do_library_list(config: &AppConfig, out: &mut Output) {
+    for (name, profile) in config.libraries.iter() {
+        out.emit(profile_record(config, name, profile), || {
+            println!(
+                "{} {} {}{}{}",
+                if config.default_library == *name {
+                    "*"
+                } else {
+                    " "
+                },
+                STYLE_FIELD_NAME.apply_to(name),
+                *DECO_LEFT_PAR_M,
+                STYLE_FIELD_VALUE.apply_to(
+                    path::Path::new(&profile.library_path)
+                        .join(profile.library_name.clone() + ".mlib")
+                        .to_str()
+                        .unwrap_or_default()
+                ),
+                *DECO_RIGHT_PAR_M,
+            )
+        });
+    }
+}
+
+fn do_library_add(
+    opt: LibraryAdd,
+    mut config: AppConfig,
+    config_path: &path::Path,
+    out: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    if config.libraries.contains_key(&opt.name) {
+        return Err(format!("There is already a library named {}.", opt.name).into());
+    }
+    let profile = LibraryProfile {
+        library_path: opt.path.to_str().unwrap_or_default().to_string(),
+        library_name: opt.library_name.clone().unwrap_or_else(|| opt.name.clone()),
+    };
+    let existed = opt
+        .path
+        .join(profile.library_name.clone() + ".mlib")
+        .exists();
+    // Opened or created right away, so a wrong path is caught here and not on
+    // the next command.
+    if existed {
+        open_library(&profile)?;
+    } else {
+        create_library(&profile)?;
+    }
+    // The first library becomes the default one.
+    if opt._use || !config.libraries.contains_key(&config.default_library) {
+        config.default_library = opt.name.clone();
+    }
+    config.libraries.insert(opt.name.clone(), profile.clone());
+    store_config(config_path, &config)?;
+    out.emit(profile_record(&config, &opt.name, &profile), || {
+        println!(
+            "{} {}",
+            STYLE_FIELD_NAME.apply_to(if existed {
+                "Added library"
+            } else {
+                "Created library"
+            }),
+            STYLE_FIELD_VALUE.apply_to(&opt.name)
+        )
+    });
+    Ok(())
+}
+
+pub fn do_library(
+    opt: LibraryCmd,
+    mut config: AppConfig,
+    config_path: &path::Path,
+    out: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    match opt.subcmd {
+        LibraryCommand::List => {
+            do_library_list(&config, out);
+            Ok(())
+        }
+        LibraryCommand::Add(opt) => do_library_add(opt, config, config_path, out),
+        LibraryCommand::Use(opt) => {
+            let (name, profile) = config.profile(Some(&opt.name))?;
+            let profile = profile.clone();
+            config.default_library = name.clone();
+            store_config(config_path, &config)?;
+            out.emit(profile_record(&config, &name, &profile), || {
+                println!(
+                    "{} {}",
+                    STYLE_FIELD_NAME.apply_to("Now using library"),
+                    STYLE_FIELD_VALUE.apply_to(&name)
+                )
+            });
+            Ok(())
+        }
+        LibraryCommand::Forget(opt) => {
+            let (name, _) = config.profile(Some(&opt.name))?;
+            if config.default_library == name {
+                return Err(
+                    format!("{} is the default library, use another one first.", name).into(),
+                );
+            }
+            config.libraries.remove(&name);
+            store_config(config_path, &config)?;
+            if out.is_text() {
+                println!(
+                    "{} {}",
+                    STYLE_FIELD_NAME.apply_to("Forgot library"),
+                    STYLE_FIELD_VALUE.apply_to(&name)
+                );
+            }
+            Ok(())
+        }
+    }
+}
@@ -3,6 +3,7 @@
 #[macro_use]
 extern crate lazy_static;
 
+use std::collections::BTreeMap;
 use std::path::{Path, PathBuf};
 
 use confy;
@@ -13,30 +14,61 @@ use serde::{Deserialize, Serialize};
 use shiromana_rs::library::{Library, LibrarySummary};
 
 use add_image::*;
+use backup::*;
 use command::*;
+use config::*;
 use ctrlc;
+use dupes::*;
+use edit::*;
+use export::*;
 use library::*;
+use output::{Output, OutputFormat};
 use prompter::*;
+use series::*;
 use std::error::Error;
 use std::str::FromStr;
 use std::sync::mpsc::channel;
+use tag::*;
+use thumb::*;
+use verify::*;
 
+mod add_dir;
 mod add_image;
+mod backup;
 mod command;
+mod config;
+mod dupes;
+mod edit;
+mod export;
+mod fetch;
+mod hasher;
+mod image_meta;
+mod incremental;
+mod journal;
 mod library;
+mod output;
+mod phash;
+mod pipeline;
+mod preview;
+mod progress;
 mod prompter;
+mod query;
+mod series;
+mod tag;
+mod thumb;
+mod verify;
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct AppConfig {
-    version: u8,
+pub const DEFAULT_PROFILE: &str = "default";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LibraryProfile {
     library_path: String,
     library_name: String,
 }
 
-impl ::std::default::Default for AppConfig {
+impl ::std::default::Default for LibraryProfile {
     fn default() -> Self {
         Self {
-            version: 1,
             library_path: directories_next::UserDirs::new()
                 .unwrap()
                 .picture_dir()
@@ -49,10 +81,57 @@ impl ::std::default::Default for AppConfig {
     }
 }
 
+// Libraries are kept as named profiles, `default_library` is the one used
+// when no --library is given.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct AppConfig {
+    version: u8,
+    default_library: String,
+    libraries: BTreeMap<String, LibraryProfile>,
+}
+
+impl ::std::default::Default for AppConfig {
+    fn default() -> Self {
+        let mut libraries = BTreeMap::new();
+        libraries.insert(DEFAULT_PROFILE.to_string(), LibraryProfile::default());
+        Self {
+            version: CONFIG_VERSION,
+            default_library: DEFAULT_PROFILE.to_string(),
+            libraries,
+        }
+    }
+}
+
+impl AppConfig {
+    // No library at all, until the first one is added.
+    fn empty() -> Self {
+        Self {
+            version: CONFIG_VERSION,
+            default_library: String::new(),
+            libraries: BTreeMap::new(),
+        }
+    }
+
+    // Name and profile of the library to use.
+    pub fn profile(&self, name: Option<&str>) -> Result<(String, &LibraryProfile), String> {
+        let name = name.unwrap_or(&self.default_library);
+        match self.libraries.get(name) {
+            Some(v) => Ok((name.to_string(), v)),
+            None if name.is_empty() => {
+                Err("There is no library yet, add one with `library add`.".to_string())
+            }
+            None => Err(format!(
+                "There is no library named {}, see `library list`.",
+                name
+            )),
+        }
+    }
+}
+
 #[cfg(debug_assertions)]
-fn purge_library(config: &AppConfig) {
+fn purge_library(profile: &LibraryProfile) {
     std::fs::remove_dir_all(
-        config.library_path.clone() + "/" + config.library_name.as_str() + ".mlib",
+        profile.library_path.clone() + "/" + profile.library_name.as_str() + ".mlib",
     )
     .unwrap_or(());
 }
@@ -65,79 +144,95 @@ fn purge(config_path: &PathBuf) {
             .blue()
     );
     std::fs::remove_dir_all(config_path.clone().parent().unwrap()).unwrap_or(());
-    purge_library(&AppConfig::default());
+    purge_library(&LibraryProfile::default());
 }
 
 #[cfg(debug_assertions)]
-fn recreate(config: &AppConfig) {
+fn recreate(config: &AppConfig, profile: &LibraryProfile) {
     println!(
         "{}",
         style("Creating configuration file and default library.").blue()
     );
     confy::store("shiromana-cli", "config", &config).unwrap();
-    let _library = match create_library(&config) {
+    let _library = match create_library(profile) {
         Ok(v) => v,
         Err(e) => panic!(
             "Error when creating Library {} at {} due to {}.",
-            config.library_name.clone() + ".mlib",
-            config.library_path,
+            profile.library_name.clone() + ".mlib",
+            profile.library_path,
             e
         ),
     };
 }
 
-fn load_config(config_path: Option<PathBuf>) -> Result<(AppConfig, Library), Box<dyn Error>> {
-    let config_path = config_path.unwrap_or(
+fn config_file_path(config_path: Option<PathBuf>) -> PathBuf {
+    config_path.unwrap_or(
         match confy::get_configuration_file_path("shiromana-cli", "config") {
             Ok(v) => v,
             Err(e) => {
                 panic!("Cannot get the path to configuration file due to {}", e);
             }
         },
-    );
+    )
+}
+
+// The configuration alone, for commands which must work without opening a
+// library.
+fn read_config(config_path: &Path) -> Result<AppConfig, Box<dyn Error>> {
+    if !config_path.exists() {
+        return Ok(AppConfig::empty());
+    }
+    load_config_file(config_path)
+}
+
+fn load_config(
+    config_path: Option<PathBuf>,
+    library: Option<&str>,
+) -> Result<(AppConfig, Library), Box<dyn Error>> {
+    let config_path = config_file_path(config_path);
 
     #[cfg(feature = "purge-every-time")]
     purge(&config_path);
     #[cfg(feature = "auto-create")]
-    recreate(&AppConfig::default());
+    recreate(&AppConfig::default(), &LibraryProfile::default());
 
     let (config, library) = if config_path.exists() {
-        let config = match confy::load_path(&config_path) {
-            Ok(v) => v,
-            Err(e) => {
-                panic!(
-                    "Cannot load configuration file at {} due to {}.",
-                    config_path.to_str().unwrap(),
-                    e
-                );
-            }
-        };
-        let library = match open_library(&config) {
+        let config = load_config_file(&config_path)?;
+        let (_, profile) = config.profile(library)?;
+        let library = match open_library(profile) {
             Ok(v) => v,
             Err(e) => panic!(
                 "Error when opening Library {} at {} due to {}.",
-                config.library_name + ".mlib",
-                config.library_path,
+                profile.library_name.clone() + ".mlib",
+                profile.library_path,
                 e
             ),
         };
         (config, library)
     } else {
-        let config = AppConfig {
-            version: 1,
-            library_path: match ask_for_location(true, AppConfig::default().library_path) {
+        let default = LibraryProfile::default();
+        let profile = LibraryProfile {
+            library_path: match ask_for_location(true, default.library_path) {
                 Ok(v) => v,
                 Err(e) => {
                     panic!("User input processing error due to: {}", e);
                 }
             },
-            library_name: match ask_for_library_name(AppConfig::default().library_name) {
+            library_name: match ask_for_library_name(default.library_name) {
                 Ok(v) => v,
                 Err(e) => {
                     panic!("User input processing error due to: {}", e);
                 }
             },
         };
+        let name = library.unwrap_or(DEFAULT_PROFILE).to_string();
+        let mut libraries = BTreeMap::new();
+        libraries.insert(name.clone(), profile.clone());
+        let config = AppConfig {
+            version: CONFIG_VERSION,
+            default_library: name,
+            libraries,
+        };
         let config_dir = config_path.parent();
         if config_dir.is_none() {
             panic!("Config file's directory cannot be resolved. Why?");
@@ -147,12 +242,12 @@ fn load_config(config_path: Option<PathBuf>) -> Result<(AppConfig, Library), Box
             }
         }
         confy::store_path(config_path, &config).unwrap();
-        let library = match create_library(&config) {
+        let library = match create_library(&profile) {
             Ok(v) => v,
             Err(e) => panic!(
                 "Error when creating Library {} at {} due to {}.",
-                config.library_name + ".mlib",
-                config.library_path,
+                profile.library_name + ".mlib",
+                profile.library_path,
                 e
             ),
         };
@@ -161,6 +256,7 @@ fn load_config(config_path: Option<PathBuf>) -> Result<(AppConfig, Library), Box
     Ok((config, library))
 }
 
+use chrono::{DateTime, Local, NaiveDate, TimeZone};
 use clap::{App, ArgGroup, Clap, ValueHint};
 use shiromana_rs::media::{Media, MediaType};
 use shiromana_rs::misc::Uuid;
@@ -171,6 +267,11 @@ use std::process::exit;
 struct Opts {
     #[clap(short, long, value_hint = ValueHint::FilePath)]
     config: Option<String>,
+    /// Name of the library profile to use instead of the default one.
+    #[clap(long, global = true)]
+    library: Option<String>,
+    #[clap(short, long, global = true, default_value = "text", possible_values = &["text", "json", "ndjson"])]
+    output: OutputFormat,
     #[clap(subcommand)]
     subcmd: SubCommand,
 }
@@ -181,6 +282,19 @@ enum SubCommand {
     Info(Info),
     Add(Add),
     Create(Create),
+    List(List),
+    Search(Search),
+    Remove(Remove),
+    Edit(Edit),
+    Tag(Tag),
+    Series(Series),
+    Dupes(Dupes),
+    Thumb(Thumb),
+    Verify(Verify),
+    Export(Export),
+    Backup(Backup),
+    Restore(Restore),
+    Library(LibraryCmd),
     Clean,
     Test,
 }
@@ -190,6 +304,39 @@ pub struct Info {
     media: Option<String>,
     #[clap(short, long)]
     detail: bool,
+    /// Show series and tags as bare UUIDs in the detailed view.
+    #[clap(long)]
+    raw_ids: bool,
+    /// Draw images in the terminal below their info.
+    #[clap(short, long)]
+    preview: bool,
+    /// How to draw the preview, auto picks from what the terminal announces.
+    #[clap(long, default_value = "auto", possible_values = &["auto", "sixel", "kitty", "iterm", "blocks"], requires = "preview")]
+    preview_protocol: PreviewProtocol,
+}
+
+#[derive(Clone)]
+pub enum PreviewProtocol {
+    Auto,
+    Sixel,
+    Kitty,
+    Iterm,
+    Blocks,
+}
+
+impl FromStr for PreviewProtocol {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "auto" => Ok(Self::Auto),
+            "sixel" => Ok(Self::Sixel),
+            "kitty" => Ok(Self::Kitty),
+            "iterm" => Ok(Self::Iterm),
+            "blocks" => Ok(Self::Blocks),
+            _ => Err(format!("{} cannot be parsed into preview protocol.", s)),
+        }
+    }
 }
 
 #[derive(Clap, Debug)]
@@ -207,12 +354,463 @@ pub struct Add {
     file: Vec<String>,
     #[clap(short, long, name = "INPUT", parse(from_os_str), value_hint = ValueHint::FilePath, validator(is_existed_as_file), group = "input")]
     input: Option<PathBuf>,
+    /// Continue an interrupted import from its journal, which is kept in the
+    /// imports directory of the library. The journal holds every other choice
+    /// of the import, only --jobs can be given with it.
+    #[clap(long, name = "JOURNAL", parse(from_os_str), value_hint = ValueHint::FilePath, validator(is_existed_as_file), group = "input", conflicts_with_all = &["move", "comment", "title", "type", "fetch", "series", "series name", "series-per-dir", "sorted", "recursive", "include glob", "exclude glob", "hidden"])]
+    resume: Option<PathBuf>,
+    /// Download URLs and add what they point to instead of the bare link.
+    #[clap(long)]
+    fetch: bool,
     #[clap(short, long, group = "series_g")]
     series: Option<Uuid>,
     #[clap(short, long, group = "series_g", name = "series name")]
     new_series: Option<String>,
+    /// Put the files of every directory into a series named after it.
+    #[clap(long, group = "series_g", requires = "recursive")]
+    series_per_dir: bool,
     #[clap(long)]
     sorted: bool,
+    #[clap(short, long)]
+    recursive: bool,
+    #[clap(long, requires = "recursive", name = "include glob")]
+    include: Vec<String>,
+    #[clap(long, requires = "recursive", name = "exclude glob")]
+    exclude: Vec<String>,
+    /// Also import hidden files and directories.
+    #[clap(long, requires = "recursive")]
+    hidden: bool,
+    /// Number of files hashed, probed and downloaded at the same time,
+    /// defaults to the number of CPUs. Files are still added in the order
+    /// given.
+    #[clap(short, long)]
+    jobs: Option<usize>,
+}
+
+#[derive(Clap)]
+pub struct List {
+    #[clap(short = 'k', long, validator(is_valid_media_type))]
+    kind: Option<MediaType>,
+    #[clap(short, long, name = "series name or UUID")]
+    series: Option<String>,
+    #[clap(short, long, name = "tag name or UUID")]
+    tag: Option<String>,
+    #[clap(long, parse(try_from_str = parse_date_since))]
+    since: Option<DateTime<Local>>,
+    #[clap(long, parse(try_from_str = parse_date_until))]
+    until: Option<DateTime<Local>>,
+    #[clap(flatten)]
+    view: ListView,
+}
+
+#[derive(Clap)]
+pub struct Search {
+    #[clap(name = "QUERY", required = true, allow_hyphen_values = true)]
+    query: Vec<String>,
+    #[clap(flatten)]
+    view: ListView,
+}
+
+#[derive(Clap)]
+pub struct ListView {
+    #[clap(long, default_value = "id", possible_values = &["size", "time", "captured", "name", "id"])]
+    sort: SortKey,
+    #[clap(short, long)]
+    reverse: bool,
+    #[clap(long)]
+    limit: Option<usize>,
+    #[clap(long, default_value = "0")]
+    offset: usize,
+    #[clap(long)]
+    table: bool,
+}
+
+pub enum SortKey {
+    Size,
+    Time,
+    Captured,
+    Name,
+    Id,
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "size" => Ok(Self::Size),
+            "time" => Ok(Self::Time),
+            "captured" => Ok(Self::Captured),
+            "name" => Ok(Self::Name),
+            "id" => Ok(Self::Id),
+            _ => Err(format!("{} cannot be parsed into sort key.", s)),
+        }
+    }
+}
+
+#[derive(Clap)]
+pub struct Remove {
+    #[clap(name = "ID, HASH or FILE NAME", required = true)]
+    media: Vec<String>,
+    #[clap(short, long)]
+    yes: bool,
+    #[clap(short, long)]
+    keep_file: bool,
+    #[clap(short, long, parse(from_os_str), value_hint = ValueHint::DirPath)]
+    export: Option<PathBuf>,
+}
+
+// Without any field flag the media is opened in $EDITOR.
+#[derive(Clap)]
+pub struct Edit {
+    #[clap(name = "ID, HASH or FILE NAME")]
+    media: String,
+    #[clap(short, long)]
+    title: Option<String>,
+    #[clap(short, long)]
+    comment: Option<String>,
+    #[clap(short = 'k', long, validator(is_valid_media_type))]
+    _type: Option<MediaType>,
+    #[clap(long)]
+    sub_type: Option<String>,
+    #[clap(long)]
+    type_addition: Option<String>,
+}
+
+#[derive(Clap)]
+pub struct Tag {
+    #[clap(subcommand)]
+    subcmd: TagCommand,
+}
+
+#[derive(Clap)]
+pub enum TagCommand {
+    Add(TagAssign),
+    Remove(TagAssign),
+    List(TagList),
+}
+
+#[derive(Clap)]
+pub struct TagAssign {
+    #[clap(name = "tag name or UUID")]
+    tag: String,
+    #[clap(name = "ID, HASH or FILE NAME", required = true)]
+    media: Vec<String>,
+    /// Create the tag and its parents if it is not existed.
+    #[clap(short, long)]
+    create: bool,
+}
+
+#[derive(Clap)]
+pub struct TagList {
+    #[clap(name = "ID, HASH or FILE NAME")]
+    media: Option<String>,
+}
+
+#[derive(Clap)]
+pub struct Series {
+    #[clap(subcommand)]
+    subcmd: SeriesCommand,
+}
+
+#[derive(Clap)]
+pub enum SeriesCommand {
+    List,
+    Show(SeriesShow),
+    Move(SeriesMove),
+    Remove(SeriesRemove),
+    Rename(SeriesRename),
+    Merge(SeriesMerge),
+    Delete(SeriesDelete),
+}
+
+#[derive(Clap)]
+pub struct SeriesShow {
+    #[clap(name = "series name or UUID")]
+    series: String,
+}
+
+#[derive(Clap)]
+pub struct SeriesMove {
+    #[clap(name = "series name or UUID")]
+    series: String,
+    #[clap(name = "ID, HASH or FILE NAME")]
+    media: String,
+    /// New position of the media in the series, starting from 1.
+    position: usize,
+}
+
+#[derive(Clap)]
+pub struct SeriesRemove {
+    #[clap(name = "series name or UUID")]
+    series: String,
+    #[clap(name = "ID, HASH or FILE NAME", required = true)]
+    media: Vec<String>,
+}
+
+#[derive(Clap)]
+pub struct SeriesRename {
+    #[clap(name = "series name or UUID")]
+    series: String,
+    name: String,
+}
+
+#[derive(Clap)]
+pub struct SeriesMerge {
+    from: String,
+    into: String,
+    #[clap(short, long)]
+    yes: bool,
+}
+
+#[derive(Clap)]
+pub struct SeriesDelete {
+    #[clap(name = "series name or UUID")]
+    series: String,
+    #[clap(short, long)]
+    yes: bool,
+}
+
+/// Find images which look alike, keep one of each group and remove the rest.
+/// Tags and series of the removed images are given to the one kept.
+#[derive(Clap)]
+pub struct Dupes {
+    /// Number of differing bits up to which two images count as alike.
+    #[clap(short, long, default_value = "6")]
+    threshold: u32,
+    /// Which image of a group to keep, `ask` prompts for every group.
+    #[clap(short, long, default_value = "ask", possible_values = &["ask", "largest", "oldest", "newest"])]
+    keep: KeepPolicy,
+    /// Only show the groups, without changing anything in the library.
+    #[clap(long)]
+    dry_run: bool,
+    #[clap(long)]
+    keep_file: bool,
+    #[clap(short, long)]
+    yes: bool,
+}
+
+pub enum KeepPolicy {
+    Ask,
+    Largest,
+    Oldest,
+    Newest,
+}
+
+impl FromStr for KeepPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "ask" => Ok(Self::Ask),
+            "largest" => Ok(Self::Largest),
+            "oldest" => Ok(Self::Oldest),
+            "newest" => Ok(Self::Newest),
+            _ => Err(format!("{} cannot be parsed into keep policy.", s)),
+        }
+    }
+}
+
+/// Print the path of the cached thumbnail of an image, making it if missing.
+#[derive(Clap)]
+pub struct Thumb {
+    #[clap(name = "ID, HASH or FILE NAME", required_unless_present = "rebuild")]
+    media: Option<String>,
+    /// Defaults to medium, or every size with --rebuild.
+    #[clap(short, long, possible_values = &["small", "medium", "large"])]
+    size: Option<ThumbSize>,
+    #[clap(short, long, default_value = "jpeg", possible_values = &["jpeg", "webp"])]
+    format: ThumbFormat,
+    /// Make the thumbnails of every image again and drop the stale ones. Only
+    /// thumbnails of the sizes and format being rebuilt are dropped.
+    #[clap(long, conflicts_with = "ID, HASH or FILE NAME")]
+    rebuild: bool,
+}
+
+/// Check that every stored file is there and unchanged, and that the library
+/// holds no files unknown to it. Exits with 1 when anything is found.
+#[derive(Clap)]
+pub struct Verify {
+    /// Compare sizes, and modification times against those of the last full
+    /// check, without hashing anything.
+    #[clap(short, long)]
+    quick: bool,
+    /// Relink moved files, and move corrupted and unknown files into the
+    /// quarantine directory of the library.
+    #[clap(long)]
+    fix: bool,
+}
+
+/// Copy the media matching a query out of the library, each next to a JSON
+/// file holding its metadata.
+#[derive(Clap)]
+pub struct Export {
+    #[clap(name = "QUERY", required = true, allow_hyphen_values = true)]
+    query: Vec<String>,
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::DirPath)]
+    to: PathBuf,
+    /// Path of each file under the target directory. Fields are id, hash,
+    /// filename, stem, ext, kind, title, series, index, time_add and captured,
+    /// like `{series}/{index:03}-{filename}` or `{kind}/{time_add:%Y/%m}/{hash}.{ext}`.
+    #[clap(long, default_value = "{filename}")]
+    template: String,
+    /// Hard link the files instead of copying them where possible.
+    #[clap(long)]
+    link: bool,
+    /// What to do when the target file is already existed, `rename` adds a
+    /// number to the new name.
+    #[clap(long, default_value = "rename", possible_values = &["rename", "skip", "overwrite"])]
+    collision: Collision,
+    #[clap(long)]
+    no_sidecar: bool,
+}
+
+/// Write the whole library into one zstd compressed tar archive, with a
+/// manifest of every file and its hash. Thumbnails are left out.
+#[derive(Clap)]
+pub struct Backup {
+    #[clap(name = "ARCHIVE", parse(from_os_str), value_hint = ValueHint::FilePath, required_unless_present = "incremental", conflicts_with = "incremental")]
+    archive: Option<PathBuf>,
+    /// Compression level of zstd, from 1 to 21.
+    #[clap(short, long, default_value = "3")]
+    level: i32,
+    /// Back up into a directory instead, copying only the media which are not
+    /// there yet. Every run is kept as a generation of its own.
+    #[clap(long, name = "DIR", parse(from_os_str), value_hint = ValueHint::DirPath)]
+    incremental: Option<PathBuf>,
+    /// Keep only this many of the newest generations.
+    #[clap(long, requires = "incremental")]
+    keep: Option<usize>,
+    /// Check the backup directory against the library instead of backing up.
+    #[clap(long, requires = "incremental", conflicts_with = "keep")]
+    verify: bool,
+}
+
+/// Rebuild a library from a backup archive under the given directory, and use
+/// it from then on.
+#[derive(Clap)]
+pub struct Restore {
+    #[clap(name = "ARCHIVE", parse(from_os_str), value_hint = ValueHint::FilePath, validator(is_existed_as_file))]
+    archive: PathBuf,
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::DirPath)]
+    to: PathBuf,
+}
+
+/// Manage the libraries known to the configuration.
+#[derive(Clap)]
+pub struct LibraryCmd {
+    #[clap(subcommand)]
+    subcmd: LibraryCommand,
+}
+
+#[derive(Clap)]
+pub enum LibraryCommand {
+    List,
+    Use(LibraryUse),
+    Add(LibraryAdd),
+    Forget(LibraryForget),
+}
+
+/// Make a library the default one.
+#[derive(Clap)]
+pub struct LibraryUse {
+    name: String,
+}
+
+/// Add a library under a name, creating it when it is not existed.
+#[derive(Clap)]
+pub struct LibraryAdd {
+    name: String,
+    /// Directory holding the `.mlib` of the library.
+    #[clap(long, parse(from_os_str), value_hint = ValueHint::DirPath)]
+    path: PathBuf,
+    /// Name of the `.mlib`, defaults to the name of the profile.
+    #[clap(long)]
+    library_name: Option<String>,
+    /// Also make it the default library.
+    #[clap(long = "use")]
+    _use: bool,
+}
+
+/// Remove a library from the configuration, its files are left alone.
+#[derive(Clap)]
+pub struct LibraryForget {
+    name: String,
+}
+
+pub enum Collision {
+    Rename,
+    Skip,
+    Overwrite,
+}
+
+impl FromStr for Collision {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "rename" => Ok(Self::Rename),
+            "skip" => Ok(Self::Skip),
+            "overwrite" => Ok(Self::Overwrite),
+            _ => Err(format!("{} cannot be parsed into collision policy.", s)),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub enum ThumbSize {
+    Small,
+    Medium,
+    Large,
+}
+
+impl ThumbSize {
+    pub fn pixels(&self) -> u32 {
+        match self {
+            Self::Small => 128,
+            Self::Medium => 256,
+            Self::Large => 512,
+        }
+    }
+}
+
+impl FromStr for ThumbSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "small" => Ok(Self::Small),
+            "medium" => Ok(Self::Medium),
+            "large" => Ok(Self::Large),
+            _ => Err(format!("{} cannot be parsed into thumbnail size.", s)),
+        }
+    }
+}
+
+pub enum ThumbFormat {
+    Jpeg,
+    Webp,
+}
+
+impl ThumbFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Jpeg => "jpg",
+            Self::Webp => "webp",
+        }
+    }
+}
+
+impl FromStr for ThumbFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "jpeg" | "jpg" => Ok(Self::Jpeg),
+            "webp" => Ok(Self::Webp),
+            _ => Err(format!("{} cannot be parsed into thumbnail format.", s)),
+        }
+    }
 }
 
 pub enum CreateType {
@@ -228,6 +826,9 @@ pub struct Create {
     comment: Option<String>,
     #[clap(short, long)]
     uuid_only: bool,
+    /// Create missing parent tags of a hierarchical tag like `artist/foo`.
+    #[clap(short, long)]
+    parents: bool,
 }
 
 impl FromStr for CreateType {
@@ -244,11 +845,11 @@ impl FromStr for CreateType {
     }
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
+fn main() {
     let (exit_sig_tx, exit_sig_rx) = channel();
 
     ctrlc::set_handler(move || {
-        println!("User Input Interrupt.");
+        eprintln!("User Input Interrupt.");
         exit_sig_tx.send(()).expect("Error while send exit signal.");
     })
     .expect("Cannot Set Ctrl-C Interrupt handler.");
@@ -262,18 +863,57 @@ fn main() -> Result<(), Box<dyn Error>> {
     };
 
     let opts: Opts = Opts::parse();
-    let config_path = opts.config.map(|v| PathBuf::from(v));
-    let (cfg, mut lib) = load_config(config_path)?;
+    let mut out = Output::new(opts.output);
+    // Errors end the output as a record too, so the output stays parseable.
+    if let Err(e) = run(opts, check_exit, &mut out) {
+        out.error(e.to_string(), || eprintln!("Error: {:?}", e));
+        out.finish();
+        exit(1);
+    }
+}
+
+fn run<F: Fn() -> bool>(opts: Opts, check_exit: F, out: &mut Output) -> Result<(), Box<dyn Error>> {
+    let config_path = opts.config.map(PathBuf::from);
+    let library = opts.library.as_deref();
+    // Restoring must not need the library in use, which may be the lost one,
+    // and libraries are managed without opening any of them.
+    match opts.subcmd {
+        SubCommand::Restore(opt) => {
+            let config_path = config_file_path(config_path);
+            let cfg = read_config(&config_path)?;
+            return do_restore(opt, cfg, library, &config_path, out);
+        }
+        SubCommand::Library(opt) => {
+            let config_path = config_file_path(config_path);
+            let cfg = read_config(&config_path)?;
+            return do_library(opt, cfg, &config_path, out);
+        }
+        _ => (),
+    }
+    let (cfg, mut lib) = load_config(config_path, library)?;
     match opts.subcmd {
-        SubCommand::Info(opt) => do_info(opt, cfg, lib),
-        SubCommand::Add(opt) => do_add(opt, cfg, &mut lib, check_exit),
-        SubCommand::Create(opt) => do_create(opt, cfg, &mut lib),
+        SubCommand::Info(opt) => do_info(opt, cfg, lib, out),
+        SubCommand::Add(opt) => do_add(opt, cfg, &mut lib, check_exit, out),
+        SubCommand::Create(opt) => do_create(opt, cfg, &mut lib, out),
+        SubCommand::List(opt) => do_list(opt, cfg, lib, out),
+        SubCommand::Search(opt) => do_search(opt, cfg, lib, out),
+        SubCommand::Remove(opt) => do_remove(opt, cfg, &mut lib, out),
+        SubCommand::Edit(opt) => do_edit(opt, cfg, &mut lib, out),
+        SubCommand::Tag(opt) => do_tag(opt, cfg, &mut lib, out),
+        SubCommand::Series(opt) => do_series(opt, cfg, &mut lib, out),
+        SubCommand::Dupes(opt) => do_dupes(opt, cfg, &mut lib, out),
+        SubCommand::Thumb(opt) => do_thumb(opt, cfg, &lib, check_exit, out),
+        SubCommand::Verify(opt) => do_verify(opt, cfg, &mut lib, check_exit, out),
+        SubCommand::Export(opt) => do_export(opt, cfg, &lib, out),
+        SubCommand::Backup(opt) => do_backup(opt, cfg, &lib, check_exit, out),
+        SubCommand::Restore(_) | SubCommand::Library(_) => unreachable!(),
         SubCommand::Clean => {
             #[cfg(debug_assertions)]
             {
                 drop(lib);
-                purge_library(&cfg);
-                recreate(&cfg);
+                let (_, profile) = cfg.profile(library)?;
+                purge_library(profile);
+                recreate(&cfg, profile);
             }
             Ok(())
         }
@@ -299,7 +939,7 @@ fn is_existed_as_file(v: &str) -> Result<(), String> {
     }
 }
 
-fn is_valid_media_type(v: &str) -> Result<(), String> {
+pub(crate) fn is_valid_media_type(v: &str) -> Result<(), String> {
     let k = MediaType::from_str(v.trim()).map_err(|_| "Unsupported Media Type.".to_string())?;
     match k {
         MediaType::Other => {
@@ -312,3 +952,150 @@ fn is_valid_media_type(v: &str) -> Result<(), String> {
         _ => Ok(()),
     }
 }
+
+fn parse_date(v: &str, end_of_day: bool) -> Result<DateTime<Local>, String> {
+    if let Ok(v) = DateTime::parse_from_rfc3339(v.trim()) {
+        return Ok(v.with_timezone(&Local));
+    }
+    let date = NaiveDate::parse_from_str(v.trim(), "%Y-%m-%d")
+        .map_err(|_| format!("{} is not a date like 2021-01-31.", v))?;
+    let time = if end_of_day {
+        date.and_hms_opt(23, 59, 59)
+    } else {
+        date.and_hms_opt(0, 0, 0)
+    }
+    .unwrap();
+    Local
+        .from_local_datetime(&time)
+        .earliest()
+        .ok_or(format!("{} is not a valid local time.", v))
+}
+
+fn parse_date_since(v: &str) -> Result<DateTime<Local>, String> {
+    parse_date(v, false)
+}
+
+fn parse_date_until(v: &str) -> Result<DateTime<Local>, String> {
+    parse_date(v, true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Opts, clap::Error> {
+        Opts::try_parse_from(std::iter::once("shiromana-cli").chain(args.iter().copied()))
+    }
+
+    #[test]
+    fn resume_takes_no_other_choices() {
+        let dir = tempfile::tempdir().unwrap();
+        let journal = dir.path().join("import.journal");
+        std::fs::write(&journal, "").unwrap();
+        let journal = journal.to_str().unwrap();
+        assert!(parse(&["add", "--resume", journal]).is_ok());
+        assert!(parse(&["add", "--resume", journal, "--jobs", "2"]).is_ok());
+        for flag in [
+            &["--move"][..],
+            &["--fetch"],
+            &["--sorted"],
+            &["--title", "x"],
+            &["--series", "00000000-0000-0000-0000-000000000000"],
+            &["--new-series", "x"],
+            &["-k", "image"],
+        ]
+        .iter()
+        {
+            let mut args = vec!["add", "--resume", journal];
+            args.extend_from_slice(flag);
+            assert!(parse(&args).is_err(), "{:?} is accepted", flag);
+        }
+    }
+
+    #[test]
+    fn tag_commands() {
+        match parse(&["tag", "add", "artist/foo", "1", "2", "--create"]).map(|v| v.subcmd) {
+            Ok(SubCommand::Tag(Tag {
+                subcmd: TagCommand::Add(v),
+            })) => {
+                assert_eq!(v.tag, "artist/foo");
+                assert_eq!(v.media, vec!["1", "2"]);
+                assert!(v.create);
+            }
+            _ => panic!("not parsed as tag add"),
+        }
+        assert!(parse(&["tag", "remove", "artist/foo"]).is_err());
+        assert!(parse(&["tag", "list"]).is_ok());
+    }
+
+    #[test]
+    fn dates() {
+        let since = parse_date_since("2021-01-31").unwrap();
+        let until = parse_date_until(" 2021-01-31 ").unwrap();
+        assert_eq!(since.format("%F %T").to_string(), "2021-01-31 00:00:00");
+        assert_eq!(until.format("%F %T").to_string(), "2021-01-31 23:59:59");
+        let exact = parse_date_until("2021-01-31T10:00:00+00:00").unwrap();
+        assert_eq!(exact.timestamp(), 1612087200);
+        assert_eq!(
+            parse_date_since("31/01/2021").unwrap_err(),
+            "31/01/2021 is not a date like 2021-01-31."
+        );
+    }
+
+    #[test]
+    fn list_options() {
+        let opts = parse(&[
+            "list",
+            "-k",
+            "image",
+            "--since",
+            "2021-01-01",
+            "--sort",
+            "size",
+            "-r",
+            "--limit",
+            "5",
+            "--offset",
+            "2",
+        ])
+        .unwrap();
+        match opts.subcmd {
+            SubCommand::List(v) => {
+                assert!(v.kind == Some(MediaType::Image));
+                assert!(v.since.is_some() && v.until.is_none());
+                assert!(matches!(v.view.sort, SortKey::Size));
+                assert!(v.view.reverse);
+                assert_eq!((v.view.limit, v.view.offset), (Some(5), 2));
+            }
+            _ => panic!("not parsed as list"),
+        }
+        assert!(parse(&["list", "--sort", "rating"]).is_err());
+        assert!(parse(&["list", "--until", "2021-02-30"]).is_err());
+    }
+
+    #[test]
+    fn profiles() {
+        let mut config = AppConfig::default();
+        let (name, _) = config.profile(None).unwrap();
+        assert_eq!(name, DEFAULT_PROFILE);
+        assert_eq!(
+            config.profile(Some("work")).unwrap_err(),
+            "There is no library named work, see `library list`."
+        );
+        config
+            .libraries
+            .insert("work".to_string(), LibraryProfile::default());
+        assert_eq!(config.profile(Some("work")).unwrap().0, "work");
+    }
+
+    #[test]
+    fn no_config_has_no_library() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = read_config(&dir.path().join("config.toml")).unwrap();
+        assert!(config.libraries.is_empty());
+        assert_eq!(
+            config.profile(None).unwrap_err(),
+            "There is no library yet, add one with `library add`."
+        );
+    }
+}
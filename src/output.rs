@@ -0,0 +1,471 @@
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::str::FromStr;
+
+use serde::Serialize;
+use shiromana_rs::library::{Library, MediaSet, MediaSetType};
+use shiromana_rs::media::{Media, MediaDetail};
+use shiromana_rs::misc::Uuid;
+
+use crate::fetch::Origin;
+use crate::image_meta::capture_time;
+use crate::ThumbSize;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+    Ndjson,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            "ndjson" => Ok(Self::Ndjson),
+            _ => Err(format!("{} cannot be parsed into output format.", s)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct MediaRecord {
+    pub id: u64,
+    pub library_uuid: String,
+    pub hash: String,
+    pub filename: String,
+    pub filepath: String,
+    pub filesize: u64,
+    pub kind: String,
+    pub time_add: String,
+    pub captured: Option<String>,
+    pub caption: Option<String>,
+    pub sub_kind: Option<String>,
+    pub kind_addition: Option<String>,
+    pub comment: Option<String>,
+    pub series: Vec<String>,
+    pub tags: Vec<String>,
+    pub detail: Option<DetailRecord>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub origin: Option<Origin>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum DetailRecord {
+    Image {
+        width: u32,
+        height: u32,
+        color_depth: u16,
+        format: String,
+        extra: BTreeMap<String, String>,
+    },
+    // Details this version does not know the fields of.
+    Other {
+        text: String,
+    },
+}
+
+impl From<&MediaDetail> for DetailRecord {
+    fn from(detail: &MediaDetail) -> Self {
+        match detail {
+            MediaDetail::Image(v) => Self::Image {
+                width: v.width,
+                height: v.height,
+                color_depth: v.color_depth,
+                format: v.format.clone(),
+                extra: v.extra.clone().into_iter().collect(),
+            },
+            #[allow(unreachable_patterns)]
+            other => Self::Other {
+                text: other.to_string(),
+            },
+        }
+    }
+}
+
+impl From<&Media> for MediaRecord {
+    fn from(media: &Media) -> Self {
+        Self {
+            id: media.id,
+            library_uuid: media.library_uuid.to_string(),
+            hash: media.hash.clone(),
+            filename: media.filename.clone(),
+            filepath: media.filepath.clone(),
+            filesize: media.filesize as u64,
+            kind: media.kind.to_string(),
+            time_add: media.time_add.to_rfc3339(),
+            captured: capture_time(media).map(|v| v.to_rfc3339()),
+            caption: media.caption.clone(),
+            sub_kind: media.sub_kind.clone(),
+            kind_addition: media.kind_addition.clone(),
+            comment: media.comment.clone(),
+            series: media.series.iter().map(|u| u.to_string()).collect(),
+            tags: media.tag.iter().map(|u| u.to_string()).collect(),
+            detail: media.detail.as_ref().map(|v| v.into()),
+            origin: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct LibraryRecord {
+    pub name: String,
+    pub master_name: Option<String>,
+    pub uuid: String,
+    pub path: String,
+    pub schema: String,
+    pub media_count: u64,
+    pub series_count: u64,
+    pub tags_count: u64,
+    pub media_size: u64,
+}
+
+impl From<&Library> for LibraryRecord {
+    fn from(lib: &Library) -> Self {
+        let summary = lib.get_summary();
+        Self {
+            name: lib.get_library_name().to_string(),
+            master_name: lib.get_master_name().map(|v| v.to_string()),
+            uuid: lib.uuid.to_string(),
+            path: lib.get_path().to_string(),
+            schema: lib.get_schema().to_string(),
+            media_count: summary.media_count,
+            series_count: summary.series_count,
+            tags_count: summary.tags_count,
+            media_size: summary.media_size,
+        }
+    }
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum AddRecord {
+    Added {
+        file: String,
+        id: u64,
+        kind: String,
+    },
+    Existed {
+        file: String,
+        id: Option<u64>,
+        kind: Option<String>,
+    },
+    Failed {
+        file: String,
+        error: String,
+    },
+}
+
+#[derive(Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum RemoveRecord {
+    Removed {
+        id: u64,
+        filename: String,
+        exported: Option<String>,
+        file_kept: bool,
+    },
+    NotFound {
+        target: String,
+    },
+    Failed {
+        id: u64,
+        filename: String,
+        error: String,
+    },
+}
+
+#[derive(Serialize)]
+pub struct SetRecord {
+    pub kind: String,
+    pub name: String,
+    pub uuid: String,
+    pub created: bool,
+}
+
+pub fn set_kind_name(kind: MediaSetType) -> &'static str {
+    match kind {
+        MediaSetType::Series => "series",
+        MediaSetType::Tag => "tag",
+    }
+}
+
+impl SetRecord {
+    pub fn new(kind: MediaSetType, name: String, uuid: Uuid, created: bool) -> Self {
+        Self {
+            kind: set_kind_name(kind).to_string(),
+            name,
+            uuid: uuid.to_string(),
+            created,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct SetInfoRecord {
+    pub kind: String,
+    pub name: String,
+    pub uuid: String,
+    pub comment: Option<String>,
+    pub media_count: usize,
+}
+
+impl From<&MediaSet> for SetInfoRecord {
+    fn from(set: &MediaSet) -> Self {
+        Self {
+            kind: set_kind_name(set.kind).to_string(),
+            name: set.name.clone(),
+            uuid: set.uuid.to_string(),
+            comment: set.comment.clone(),
+            media_count: set.media.len(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct MembershipRecord {
+    pub kind: String,
+    pub action: String,
+    pub uuid: String,
+    pub media: Vec<u64>,
+}
+
+#[derive(Serialize)]
+pub struct ClusterRecord {
+    pub media: Vec<u64>,
+    pub kept: Option<u64>,
+    pub removed: Vec<u64>,
+}
+
+#[derive(Serialize)]
+pub struct ThumbRecord {
+    pub id: u64,
+    pub size: u32,
+    pub path: String,
+    pub created: bool,
+}
+
+impl ThumbRecord {
+    pub fn new(id: u64, size: &ThumbSize, path: &Path, created: bool) -> Self {
+        Self {
+            id,
+            size: size.pixels(),
+            path: path.to_str().unwrap_or_default().to_string(),
+            created,
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct VerifyRecord {
+    pub problem: String,
+    pub id: Option<u64>,
+    pub path: String,
+    pub detail: Option<String>,
+    pub fix: Option<String>,
+}
+
+#[derive(Serialize, Clone)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum ExportRecord {
+    Exported { id: u64, path: String, linked: bool },
+    Skipped { id: u64 },
+    Failed { id: u64, error: String },
+}
+
+#[derive(Serialize)]
+pub struct BackupRecord {
+    pub archive: String,
+    pub library_uuid: String,
+    pub files: usize,
+    pub size: u64,
+}
+
+#[derive(Serialize)]
+pub struct IncrementalRecord {
+    pub target: String,
+    pub generation: String,
+    pub library_uuid: String,
+    pub files: usize,
+    pub copied: usize,
+    pub copied_size: u64,
+    pub pruned: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct RestoreRecord {
+    pub archive: String,
+    pub path: String,
+    pub library_uuid: String,
+    pub files: usize,
+}
+
+#[derive(Serialize)]
+pub struct ProfileRecord {
+    pub name: String,
+    pub path: String,
+    pub library_name: String,
+    pub default: bool,
+}
+
+#[derive(Serialize)]
+pub struct ErrorRecord {
+    pub message: String,
+}
+
+// Every record carries a `record` field naming its type, so a stream mixing
+// several kinds of records can still be told apart line by line.
+#[derive(Serialize)]
+#[serde(tag = "record", rename_all = "snake_case")]
+pub enum Record {
+    // Boxed, being many times the size of any other record.
+    Media(Box<MediaRecord>),
+    Library(LibraryRecord),
+    Add(AddRecord),
+    Remove(RemoveRecord),
+    Set(SetRecord),
+    SetInfo(SetInfoRecord),
+    Membership(MembershipRecord),
+    Cluster(ClusterRecord),
+    Thumb(ThumbRecord),
+    Verify(VerifyRecord),
+    Export(ExportRecord),
+    Backup(BackupRecord),
+    Incremental(IncrementalRecord),
+    Restore(RestoreRecord),
+    Profile(ProfileRecord),
+    Error(ErrorRecord),
+}
+
+// `json` prints all records as one array when the command finishes, `ndjson`
+// prints each record on its own line as soon as it is emitted.
+pub struct Output {
+    format: OutputFormat,
+    records: Vec<Record>,
+    finished: bool,
+}
+
+impl Output {
+    pub fn new(format: OutputFormat) -> Self {
+        Self {
+            format,
+            records: vec![],
+            finished: false,
+        }
+    }
+
+    pub fn is_text(&self) -> bool {
+        self.format == OutputFormat::Text
+    }
+
+    pub fn emit<F: FnOnce()>(&mut self, record: Record, text: F) {
+        match self.format {
+            OutputFormat::Text => text(),
+            OutputFormat::Json => self.records.push(record),
+            OutputFormat::Ndjson => println!("{}", serde_json::to_string(&record).unwrap()),
+        }
+    }
+
+    pub fn error<F: FnOnce()>(&mut self, message: String, text: F) {
+        self.emit(Record::Error(ErrorRecord { message }), text);
+    }
+
+    // Called on drop as well, only needed before leaving via `exit`.
+    pub fn finish(&mut self) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+        if self.format == OutputFormat::Json {
+            println!("{}", serde_json::to_string_pretty(&self.records).unwrap());
+        }
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use shiromana_rs::media::ImageDetail;
+
+    #[test]
+    fn formats() {
+        assert!(OutputFormat::from_str("JSON").unwrap() == OutputFormat::Json);
+        assert!(OutputFormat::from_str("ndjson").unwrap() == OutputFormat::Ndjson);
+        assert!(OutputFormat::from_str("xml").is_err());
+    }
+
+    #[test]
+    fn records_are_tagged() {
+        let record = Record::Error(ErrorRecord {
+            message: "broken".to_string(),
+        });
+        assert_eq!(
+            serde_json::to_string(&record).unwrap(),
+            r#"{"record":"error","message":"broken"}"#
+        );
+        let record = Record::Export(ExportRecord::Skipped { id: 3 });
+        assert_eq!(
+            serde_json::to_string(&record).unwrap(),
+            r#"{"record":"export","status":"skipped","id":3}"#
+        );
+    }
+
+    #[test]
+    fn tag_records() {
+        let set = MediaSet {
+            uuid: Uuid::nil(),
+            kind: MediaSetType::Tag,
+            name: "artist/foo".to_string(),
+            comment: None,
+            media: vec![1, 2],
+        };
+        let value = serde_json::to_value(Record::SetInfo((&set).into())).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "record": "set_info",
+                "kind": "tag",
+                "name": "artist/foo",
+                "uuid": "00000000-0000-0000-0000-000000000000",
+                "comment": null,
+                "media_count": 2,
+            })
+        );
+    }
+
+    #[test]
+    fn image_detail_is_structured() {
+        let mut extra = std::collections::HashMap::new();
+        extra.insert("exif.Model".to_string(), "X100".to_string());
+        extra.insert("dhash".to_string(), "00ff".to_string());
+        let detail = MediaDetail::Image(ImageDetail {
+            width: 640,
+            height: 480,
+            color_depth: 24,
+            format: "JPEG".to_string(),
+            extra,
+        });
+        let value = serde_json::to_value(DetailRecord::from(&detail)).unwrap();
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "kind": "image",
+                "width": 640,
+                "height": 480,
+                "color_depth": 24,
+                "format": "JPEG",
+                "extra": {"dhash": "00ff", "exif.Model": "X100"},
+            })
+        );
+    }
+}
@@ -0,0 +1,81 @@
+use image::imageops::FilterType;
+use image::DynamicImage;
+use shiromana_rs::media::{Media, MediaDetail};
+
+// The difference hash of an image is kept in `ImageDetail::extra` as 16 hex
+// digits.
+pub const PERCEPTUAL_HASH_KEY: &str = "dhash";
+
+// dHash: shrink to 9x8 in grey and set a bit for every pixel brighter than its
+// right neighbour. It survives scaling, recompression and small colour edits.
+pub fn dhash(image: &DynamicImage) -> u64 {
+    let small = image.resize_exact(9, 8, FilterType::Triangle).to_luma8();
+    let mut hash = 0u64;
+    for y in 0..8 {
+        for x in 0..8 {
+            hash <<= 1;
+            if small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0] {
+                hash |= 1;
+            }
+        }
+    }
+    hash
+}
+
+pub fn to_hex(hash: u64) -> String {
+    format!("{:016x}", hash)
+}
+
+pub fn media_dhash(media: &Media) -> Option<u64> {
+    match &media.detail {
+        Some(MediaDetail::Image(detail)) => detail
+            .extra
+            .get(PERCEPTUAL_HASH_KEY)
+            .and_then(|v| u64::from_str_radix(v, 16).ok()),
+        _ => None,
+    }
+}
+
+pub fn distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{GrayImage, Luma};
+
+    fn gradient(width: u32, height: u32, falling: bool) -> DynamicImage {
+        DynamicImage::ImageLuma8(GrayImage::from_fn(width, height, |x, _| {
+            let v = (x * 255 / (width - 1)) as u8;
+            Luma([if falling { 255 - v } else { v }])
+        }))
+    }
+
+    #[test]
+    fn hashes() {
+        assert_eq!(dhash(&gradient(90, 80, false)), 0);
+        assert_eq!(dhash(&gradient(90, 80, true)), u64::MAX);
+        // Scaling keeps the hash.
+        assert_eq!(
+            dhash(&gradient(900, 800, true)),
+            dhash(&gradient(45, 40, true))
+        );
+    }
+
+    #[test]
+    fn distances() {
+        assert_eq!(distance(0, 0), 0);
+        assert_eq!(distance(0, u64::MAX), 64);
+        assert_eq!(distance(0b1010, 0b0110), 2);
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(to_hex(0xAB), "00000000000000ab");
+        assert_eq!(
+            u64::from_str_radix(&to_hex(u64::MAX), 16).unwrap(),
+            u64::MAX
+        );
+    }
+}
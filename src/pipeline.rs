@@ -0,0 +1,293 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::Arc;
+use std::thread;
+
+use shiromana_rs::media::{ImageDetail, MediaType};
+use shiromana_rs::misc::HashAlgo;
+
+use crate::add_image::probe_image;
+use crate::command::is_url;
+use crate::fetch::{fetch, store_origin, Fetched};
+use crate::hasher::hash_file;
+
+// Everything about a file to import that can be worked out without the library.
+// The hash is handed to the library, so it doesn't read the file a second time.
+pub struct Prepared {
+    pub kind: MediaType,
+    pub hash: Option<String>,
+    pub detail: Option<ImageDetail>,
+    pub fetched: Option<Fetched>,
+}
+
+impl Prepared {
+    // Downloads keep where they came from once the library has taken them.
+    pub fn record_origin(&self, lib_path: &Path) -> Result<(), Box<dyn Error>> {
+        match (&self.fetched, &self.hash) {
+            (Some(fetched), Some(hash)) => store_origin(lib_path, hash, &fetched.origin),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub fn detect_kind(file: &str) -> MediaType {
+    if is_url(file) {
+        return MediaType::URL;
+    }
+    let mime_str = tree_magic::from_filepath(Path::new(file));
+    match mime_str.split('/').next().unwrap_or_default() {
+        "image" => MediaType::Image,
+        "audio" => MediaType::Audio,
+        "video" => MediaType::Video,
+        "text" => MediaType::Text,
+        _ => MediaType::Other,
+    }
+}
+
+fn prepare_local(file: &str, kind: MediaType, algo: &HashAlgo) -> Result<Prepared, String> {
+    let hash = hash_file(Path::new(file), algo)
+        .map_err(|e| format!("{} cannot be read due to {}.", file, e))?;
+    let detail = if kind == MediaType::Image {
+        Some(probe_image(Path::new(file)).map_err(|e| e.to_string())?)
+    } else {
+        None
+    };
+    Ok(Prepared {
+        kind,
+        hash: Some(hash),
+        detail,
+        fetched: None,
+    })
+}
+
+fn prepare(
+    file: &str,
+    kind: Option<MediaType>,
+    algo: &HashAlgo,
+    fetch_url: bool,
+) -> Result<Prepared, String> {
+    if !is_url(file) {
+        let kind = kind.unwrap_or_else(|| detect_kind(file));
+        return prepare_local(file, kind, algo);
+    }
+    if !fetch_url {
+        return Ok(Prepared {
+            kind: kind.unwrap_or(MediaType::URL),
+            hash: None,
+            detail: None,
+            fetched: None,
+        });
+    }
+    // Downloaded content is treated like any local file from here on.
+    let fetched = fetch(file).map_err(|e| e.to_string())?;
+    let local = fetched.file.to_str().unwrap_or_default().to_string();
+    let kind = kind.unwrap_or_else(|| detect_kind(&local));
+    let prepared = prepare_local(&local, kind, algo)?;
+    Ok(Prepared {
+        fetched: Some(fetched),
+        ..prepared
+    })
+}
+
+// Files are prepared on `jobs` worker threads and handed back in input order,
+// so the caller can stay the only writer of the library and series keep the
+// order of the input.
+pub struct Pipeline {
+    files: Arc<Vec<String>>,
+    receiver: Receiver<(usize, Result<Prepared, String>)>,
+    pending: BTreeMap<usize, Result<Prepared, String>>,
+    next: usize,
+    stop: Arc<AtomicBool>,
+}
+
+impl Pipeline {
+    pub fn start(
+        files: &[String],
+        kind: Option<MediaType>,
+        algo: HashAlgo,
+        fetch_url: bool,
+        jobs: usize,
+    ) -> Self {
+        let jobs = jobs.max(1);
+        let files = Arc::new(files.to_vec());
+        let cursor = Arc::new(AtomicUsize::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (sender, receiver) = sync_channel(jobs * 2);
+        for _ in 0..jobs {
+            let files = files.clone();
+            let cursor = cursor.clone();
+            let stop = stop.clone();
+            let sender = sender.clone();
+            let kind = kind.clone();
+            let algo = algo.clone();
+            thread::spawn(move || loop {
+                let index = cursor.fetch_add(1, Ordering::SeqCst);
+                if index >= files.len() || stop.load(Ordering::SeqCst) {
+                    break;
+                }
+                let file = &files[index];
+                // A decoder choking on a broken file must not take the import down.
+                let prepared = catch_unwind(AssertUnwindSafe(|| {
+                    prepare(file, kind.clone(), &algo, fetch_url)
+                }))
+                .unwrap_or_else(|_| Err(format!("{} cannot be processed.", file)));
+                if sender.send((index, prepared)).is_err() {
+                    break;
+                }
+            });
+        }
+        Self {
+            files,
+            receiver,
+            pending: BTreeMap::new(),
+            next: 0,
+            stop,
+        }
+    }
+}
+
+impl Iterator for Pipeline {
+    type Item = (String, Result<Prepared, String>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.files.len() {
+            return None;
+        }
+        loop {
+            if let Some(prepared) = self.pending.remove(&self.next) {
+                self.next += 1;
+                return Some((self.files[self.next - 1].clone(), prepared));
+            }
+            let (index, prepared) = self.receiver.recv().ok()?;
+            self.pending.insert(index, prepared);
+        }
+    }
+}
+
+// Downloads which will never be imported are removed as they are dropped, here
+// or on the workers once they find nobody is waiting for them.
+impl Drop for Pipeline {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fetch::find_origin;
+    use image::{ImageBuffer, Rgb};
+
+    #[test]
+    fn results_keep_input_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut files = vec![];
+        for i in 0..12 {
+            let path = dir.path().join(format!("{}.png", i));
+            if i % 3 == 0 {
+                std::fs::write(&path, "not an image").unwrap();
+            } else {
+                ImageBuffer::from_pixel(i + 1, 1, Rgb([0u8, 0, 0]))
+                    .save(&path)
+                    .unwrap();
+            }
+            files.push(path.to_str().unwrap().to_string());
+        }
+        let results: Vec<_> =
+            Pipeline::start(&files, Some(MediaType::Image), HashAlgo::SHA256, false, 4).collect();
+        assert_eq!(results.len(), files.len());
+        for (i, (file, prepared)) in results.into_iter().enumerate() {
+            assert_eq!(file, files[i]);
+            match prepared {
+                Ok(p) => {
+                    let expected = hash_file(Path::new(&file), &HashAlgo::SHA256).unwrap();
+                    assert_eq!(p.hash.unwrap(), expected);
+                    assert_eq!(p.detail.unwrap().width, i as u32 + 1);
+                }
+                Err(_) => assert_eq!(i % 3, 0),
+            }
+        }
+    }
+
+    #[test]
+    fn urls_are_kept_without_fetching() {
+        let files = vec!["https://example.com/a.png".to_string()];
+        let (file, prepared) = Pipeline::start(&files, None, HashAlgo::SHA256, false, 1)
+            .next()
+            .unwrap();
+        assert_eq!(file, files[0]);
+        let prepared = prepared.unwrap();
+        assert!(prepared.kind == MediaType::URL);
+        assert!(prepared.hash.is_none() && prepared.detail.is_none() && prepared.fetched.is_none());
+    }
+
+    // Answers a single request with `body` and returns the URL to `path`.
+    fn serve(path: &str, body: Vec<u8>) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}{}", listener.local_addr().unwrap(), path);
+        std::thread::spawn(move || {
+            use std::io::{BufRead, BufReader, Write};
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut line = String::new();
+            while reader.read_line(&mut line).unwrap() > 2 {
+                line.clear();
+            }
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n",
+                body.len()
+            )
+            .unwrap();
+            stream.write_all(&body).unwrap();
+        });
+        url
+    }
+
+    #[test]
+    fn fetched_images_are_probed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.png");
+        ImageBuffer::from_pixel(2, 2, Rgb([0u8, 0, 0]))
+            .save(&path)
+            .unwrap();
+        let url = serve("/a.png", std::fs::read(&path).unwrap());
+
+        let files = vec![url.clone()];
+        let (_, prepared) =
+            Pipeline::start(&files, Some(MediaType::Image), HashAlgo::SHA256, true, 1)
+                .next()
+                .unwrap();
+        let prepared = prepared.unwrap();
+        assert_eq!(prepared.detail.unwrap().width, 2);
+        assert_eq!(prepared.fetched.as_ref().unwrap().origin.url, url);
+        let downloaded = prepared.fetched.as_ref().unwrap().file.clone();
+        assert!(downloaded.exists());
+        drop(prepared.fetched);
+        assert!(!downloaded.exists());
+    }
+
+    #[test]
+    fn fetched_files_keep_their_origin() {
+        let url = serve("/notes.txt", b"some notes".to_vec());
+        let files = vec![url.clone()];
+        let (_, prepared) =
+            Pipeline::start(&files, Some(MediaType::Text), HashAlgo::SHA256, true, 1)
+                .next()
+                .unwrap();
+        let prepared = prepared.unwrap();
+        assert!(prepared.kind == MediaType::Text && prepared.detail.is_none());
+        let hash = prepared.hash.clone().unwrap();
+        let downloaded = &prepared.fetched.as_ref().unwrap().file;
+        assert_eq!(hash, hash_file(downloaded, &HashAlgo::SHA256).unwrap());
+
+        let lib = tempfile::tempdir().unwrap();
+        prepared.record_origin(lib.path()).unwrap();
+        drop(prepared);
+        assert_eq!(find_origin(lib.path(), &hash).unwrap().url, url);
+    }
+}
@@ -0,0 +1,258 @@
+use std::error::Error;
+use std::io::Write;
+
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, ImageOutputFormat, RgbImage};
+use shiromana_rs::library::Library;
+use shiromana_rs::media::Media;
+
+use crate::thumb::ensure_thumb;
+use crate::{PreviewProtocol, ThumbFormat, ThumbSize};
+
+// Rough size of a terminal cell in pixels, for protocols sized in pixels.
+const CELL_WIDTH: u32 = 10;
+const CELL_HEIGHT: u32 = 20;
+const MAX_COLUMNS: u16 = 80;
+const MAX_ROWS: u16 = 24;
+
+// Terminals do not tell reliably what they can draw without a round trip, so
+// go by what they announce in the environment.
+fn detect() -> PreviewProtocol {
+    let var = |name: &str| std::env::var(name).unwrap_or_default();
+    let term = var("TERM");
+    let program = var("TERM_PROGRAM");
+    if term.contains("kitty") || !var("KITTY_WINDOW_ID").is_empty() {
+        PreviewProtocol::Kitty
+    } else if program == "iTerm.app" || program == "WezTerm" {
+        PreviewProtocol::Iterm
+    } else if term.contains("sixel")
+        || term.starts_with("mlterm")
+        || term.starts_with("foot")
+        || term.starts_with("yaft")
+        || program == "mintty"
+    {
+        PreviewProtocol::Sixel
+    } else {
+        PreviewProtocol::Blocks
+    }
+}
+
+// Columns and rows the preview may take.
+fn area() -> (u32, u32) {
+    let (rows, columns) = console::Term::stdout().size();
+    (
+        columns.clamp(1, MAX_COLUMNS) as u32,
+        (rows.saturating_sub(2)).clamp(1, MAX_ROWS) as u32,
+    )
+}
+
+fn png_base64(image: &DynamicImage) -> Result<String, Box<dyn Error>> {
+    let mut png = vec![];
+    image.write_to(&mut png, ImageOutputFormat::Png)?;
+    Ok(base64::encode(&png))
+}
+
+fn kitty(image: &DynamicImage, columns: u32) -> Result<String, Box<dyn Error>> {
+    let data = png_base64(image)?;
+    let chunks: Vec<&[u8]> = data.as_bytes().chunks(4096).collect();
+    let mut s = String::new();
+    for (i, chunk) in chunks.iter().enumerate() {
+        let more = if i + 1 < chunks.len() { 1 } else { 0 };
+        let control = if i == 0 {
+            format!("f=100,a=T,c={},m={}", columns, more)
+        } else {
+            format!("m={}", more)
+        };
+        s += &format!(
+            "\x1b_G{};{}\x1b\\",
+            control,
+            std::str::from_utf8(chunk).unwrap()
+        );
+    }
+    Ok(s + "\n")
+}
+
+fn iterm(image: &DynamicImage, columns: u32) -> Result<String, Box<dyn Error>> {
+    let data = png_base64(image)?;
+    Ok(format!(
+        "\x1b]1337;File=inline=1;width={};preserveAspectRatio=1:{}\x07\n",
+        columns, data
+    ))
+}
+
+// Sixel with a fixed 6x6x6 colour cube, which is enough for a preview.
+fn sixel(image: &RgbImage) -> String {
+    let level = |v: u8| (v as u32 * 5 + 127) / 255;
+    let (width, height) = image.dimensions();
+    let index: Vec<u32> = image
+        .pixels()
+        .map(|p| level(p[0]) * 36 + level(p[1]) * 6 + level(p[2]))
+        .collect();
+    let mut s = format!("\x1bPq\"1;1;{};{}", width, height);
+    for c in 0..216 {
+        let percent = |v: u32| v * 100 / 5;
+        s += &format!(
+            "#{};2;{};{};{}",
+            c,
+            percent(c / 36),
+            percent(c / 6 % 6),
+            percent(c % 6)
+        );
+    }
+    for band in (0..height).step_by(6) {
+        let rows = (band..(band + 6).min(height)).collect::<Vec<_>>();
+        let mut colors: Vec<u32> = rows
+            .iter()
+            .flat_map(|y| (0..width).map(move |x| (x, *y)))
+            .map(|(x, y)| index[(y * width + x) as usize])
+            .collect();
+        colors.sort_unstable();
+        colors.dedup();
+        for c in colors {
+            s += &format!("#{}", c);
+            let mut run: Option<(char, usize)> = None;
+            let flush = |s: &mut String, run: Option<(char, usize)>| match run {
+                Some((ch, n)) if n > 3 => *s += &format!("!{}{}", n, ch),
+                Some((ch, n)) => *s += &ch.to_string().repeat(n),
+                None => (),
+            };
+            for x in 0..width {
+                let bits = rows.iter().enumerate().fold(0u8, |bits, (i, y)| {
+                    if index[(y * width + x) as usize] == c {
+                        bits | 1 << i
+                    } else {
+                        bits
+                    }
+                });
+                let ch = (63 + bits) as char;
+                run = match run {
+                    Some((prev, n)) if prev == ch => Some((prev, n + 1)),
+                    other => {
+                        flush(&mut s, other);
+                        Some((ch, 1))
+                    }
+                };
+            }
+            flush(&mut s, run);
+            s.push('$');
+        }
+        s.push('-');
+    }
+    s + "\x1b\\\n"
+}
+
+// Two pixels per cell: the upper half block in the colour of the top pixel
+// over a background in the colour of the bottom one.
+fn blocks(image: &RgbImage) -> String {
+    let (width, height) = image.dimensions();
+    let mut s = String::new();
+    for y in (0..height).step_by(2) {
+        for x in 0..width {
+            let top = image.get_pixel(x, y);
+            s += &format!("\x1b[38;2;{};{};{}m", top[0], top[1], top[2]);
+            if y + 1 < height {
+                let bottom = image.get_pixel(x, y + 1);
+                s += &format!("\x1b[48;2;{};{};{}m", bottom[0], bottom[1], bottom[2]);
+            }
+            s += "\u{2580}";
+        }
+        s += "\x1b[0m\n";
+    }
+    s
+}
+
+// Pixels to resize to for `blocks`, keeping the aspect ratio. Cells are about
+// twice as high as wide, which the half blocks undo.
+fn blocks_size((w, h): (u32, u32), columns: u32, rows: u32) -> (u32, u32) {
+    let fit_rows = (w as f64 * (rows * 2) as f64 / h as f64) as u32;
+    let width = columns.min(w).min(fit_rows).max(1);
+    let height = ((h as f64 * width as f64 / w as f64) as u32).max(1);
+    (width, height)
+}
+
+// Draw an image media below its info, from the large thumbnail so big files
+// are not decoded every time.
+pub fn show_preview(
+    lib: &Library,
+    media: &Media,
+    protocol: &PreviewProtocol,
+) -> Result<(), Box<dyn Error>> {
+    // Escape sequences piped into a file or another program are only noise.
+    if !console::user_attended() {
+        return Err("Output is not a terminal.".into());
+    }
+    let (thumb, _) = ensure_thumb(lib, media, &ThumbSize::Large, &ThumbFormat::Jpeg, false)?;
+    let image = image::open(thumb)?;
+    let (columns, rows) = area();
+    let protocol = match protocol {
+        PreviewProtocol::Auto => detect(),
+        v => v.clone(),
+    };
+    let rendered = match protocol {
+        PreviewProtocol::Kitty => kitty(&image, columns)?,
+        PreviewProtocol::Iterm => iterm(&image, columns)?,
+        PreviewProtocol::Sixel => sixel(
+            &image
+                .resize(
+                    columns * CELL_WIDTH,
+                    rows * CELL_HEIGHT,
+                    FilterType::Triangle,
+                )
+                .to_rgb8(),
+        ),
+        _ => {
+            let (width, height) = blocks_size(image.dimensions(), columns, rows);
+            blocks(
+                &image
+                    .resize_exact(width, height, FilterType::Triangle)
+                    .to_rgb8(),
+            )
+        }
+    };
+    let stdout = std::io::stdout();
+    let mut lock = stdout.lock();
+    lock.write_all(rendered.as_bytes())?;
+    lock.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgb;
+
+    #[test]
+    fn block_sizes() {
+        // Wide images are bound by the columns, tall ones by the rows.
+        assert_eq!(blocks_size((1600, 800), 80, 22), (80, 40));
+        assert_eq!(blocks_size((800, 1600), 80, 22), (22, 44));
+        assert_eq!(blocks_size((100, 1000), 80, 10), (2, 20));
+        // Small images are not enlarged.
+        assert_eq!(blocks_size((10, 10), 80, 24), (10, 10));
+    }
+
+    #[test]
+    fn half_blocks() {
+        let mut image = RgbImage::from_pixel(2, 3, Rgb([0, 0, 0]));
+        image.put_pixel(0, 1, Rgb([255, 0, 0]));
+        let s = blocks(&image);
+        let lines: Vec<&str> = s.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].matches('\u{2580}').count(), 2);
+        assert!(lines[0].starts_with("\x1b[38;2;0;0;0m\x1b[48;2;255;0;0m\u{2580}"));
+        // The last row of an odd height has no bottom pixel.
+        assert!(!lines[1].contains("\x1b[48;2"));
+        assert!(lines[1].ends_with("\x1b[0m"));
+    }
+
+    #[test]
+    fn sixel_runs() {
+        let image = RgbImage::from_pixel(8, 2, Rgb([255, 255, 255]));
+        let s = sixel(&image);
+        assert!(s.starts_with("\x1bPq\"1;1;8;2"));
+        assert!(s.ends_with("\x1b\\\n"));
+        // Both rows of every column are white, colour 215, in a single run.
+        assert!(s.contains("#215;2;100;100;100"));
+        assert!(s.contains("#215!8B$-"));
+    }
+}
@@ -0,0 +1,208 @@
+use std::path::Path;
+use std::thread::JoinHandle;
+use std::time::Instant;
+
+use humansize::{file_size_opts, FileSize};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ImportStatus {
+    Added,
+    Existed,
+    Failed,
+}
+
+struct Bars {
+    files: ProgressBar,
+    bytes: ProgressBar,
+    status: ProgressBar,
+    drawer: JoinHandle<()>,
+}
+
+// Progress of a bulk import. Bars are drawn on stderr only when stdout is a
+// terminal, otherwise every line is printed as it is.
+pub struct ImportProgress {
+    bars: Option<Bars>,
+    added: usize,
+    existed: usize,
+    failed: usize,
+    // Measured up front, a moved file is gone once it is imported.
+    sizes: Vec<u64>,
+    bytes: u64,
+    started: Instant,
+}
+
+pub fn file_size(file: &str) -> u64 {
+    std::fs::metadata(Path::new(file))
+        .map(|v| v.len())
+        .unwrap_or(0)
+}
+
+impl ImportProgress {
+    pub fn new(files: &[String], enabled: bool) -> Self {
+        let sizes: Vec<u64> = files.iter().map(|f| file_size(f)).collect();
+        let bars = if enabled && console::user_attended() {
+            let multi = MultiProgress::new();
+            let files_bar = multi.add(ProgressBar::new(files.len() as u64));
+            files_bar.set_style(
+                ProgressStyle::default_bar()
+                    .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} files, ETA {eta} {wide_msg}")
+                    .progress_chars("#>-"),
+            );
+            let bytes_bar = multi.add(ProgressBar::new(sizes.iter().sum()));
+            bytes_bar.set_style(
+                ProgressStyle::default_bar()
+                    .template("  [{bar:40.cyan/blue}] {bytes}/{total_bytes} at {bytes_per_sec}")
+                    .progress_chars("#>-"),
+            );
+            let status_bar = multi.add(ProgressBar::new(1));
+            status_bar.set_style(ProgressStyle::default_bar().template("  {msg}"));
+            files_bar.enable_steady_tick(100);
+            let drawer = std::thread::spawn(move || {
+                multi.join().unwrap_or(());
+            });
+            Some(Bars {
+                files: files_bar,
+                bytes: bytes_bar,
+                status: status_bar,
+                drawer,
+            })
+        } else {
+            None
+        };
+        let mut progress = Self {
+            bars,
+            added: 0,
+            existed: 0,
+            failed: 0,
+            sizes,
+            bytes: 0,
+            started: Instant::now(),
+        };
+        progress.update_status();
+        progress
+    }
+
+    fn counts(&self) -> String {
+        format!(
+            "added: {}, existed: {}, failed: {}",
+            self.added, self.existed, self.failed
+        )
+    }
+
+    fn update_status(&mut self) {
+        if let Some(bars) = &self.bars {
+            bars.status.set_message(self.counts());
+        }
+    }
+
+    pub fn start_file(&self, file: &str) {
+        if let Some(bars) = &self.bars {
+            bars.files.set_message(
+                Path::new(file)
+                    .file_name()
+                    .unwrap_or_default()
+                    .to_str()
+                    .unwrap_or_default()
+                    .to_string(),
+            );
+        }
+    }
+
+    // `position` is the index of the file in the list the progress started with.
+    pub fn finish_file(&mut self, position: usize, status: ImportStatus) {
+        match status {
+            ImportStatus::Added => self.added += 1,
+            ImportStatus::Existed => self.existed += 1,
+            ImportStatus::Failed => self.failed += 1,
+        }
+        let size = self.sizes[position];
+        self.bytes += size;
+        if let Some(bars) = &self.bars {
+            bars.files.inc(1);
+            bars.bytes.inc(size);
+        }
+        self.update_status();
+    }
+
+    pub fn is_drawing(&self) -> bool {
+        self.bars.is_some()
+    }
+
+    // Print above the bars, so that they are not torn apart.
+    pub fn println(&self, line: String) {
+        match &self.bars {
+            Some(bars) => bars.files.println(line),
+            None => println!("{}", line),
+        }
+    }
+
+    // Stop drawing and return the summary line with the throughput.
+    pub fn finish(self, interrupted: bool) -> String {
+        let elapsed = self.started.elapsed();
+        let summary = format!(
+            "{} {}, {} in {:.1}s ({}/s).",
+            if interrupted {
+                "Import interrupted,"
+            } else {
+                "Import finished,"
+            },
+            self.counts(),
+            self.bytes.file_size(file_size_opts::CONVENTIONAL).unwrap(),
+            elapsed.as_secs_f64(),
+            ((self.bytes as f64 / elapsed.as_secs_f64().max(0.001)) as u64)
+                .file_size(file_size_opts::CONVENTIONAL)
+                .unwrap()
+        );
+        if let Some(bars) = self.bars {
+            if interrupted {
+                bars.files.abandon_with_message("Interrupted.");
+                bars.bytes.abandon();
+                bars.status.abandon();
+            } else {
+                bars.files.finish_with_message("Done.");
+                bars.bytes.finish();
+                bars.status.finish();
+            }
+            bars.drawer.join().unwrap_or(());
+        }
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_of_moved_files_count() {
+        let dir = tempfile::tempdir().unwrap();
+        let files: Vec<String> = [("a", 1000), ("b", 24)]
+            .iter()
+            .map(|(name, len)| {
+                let path = dir.path().join(name);
+                std::fs::write(&path, vec![0u8; *len]).unwrap();
+                path.to_str().unwrap().to_string()
+            })
+            .collect();
+        let mut progress = ImportProgress::new(&files, false);
+        for f in files.iter() {
+            std::fs::remove_file(f).unwrap();
+        }
+        progress.finish_file(0, ImportStatus::Added);
+        progress.finish_file(1, ImportStatus::Existed);
+        let summary = progress.finish(false);
+        assert!(summary.starts_with("Import finished, added: 1, existed: 1, failed: 0, 1 KB in"));
+    }
+
+    #[test]
+    fn missing_files_count_nothing() {
+        let mut progress = ImportProgress::new(&["https://example.com/a.png".to_string()], false);
+        progress.finish_file(0, ImportStatus::Failed);
+        assert!(progress
+            .finish(true)
+            .starts_with("Import interrupted, added: 0, existed: 0, failed: 1, 0 B in"));
+    }
+}
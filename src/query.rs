@@ -0,0 +1,661 @@
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, Local, NaiveDate, TimeZone};
+use shiromana_rs::library::{Library, MediaSetType};
+use shiromana_rs::media::{Media, MediaType};
+use shiromana_rs::misc::{Error as LibError, Uuid};
+
+use crate::command::resolve_set;
+use crate::image_meta::capture_time;
+use crate::is_valid_media_type;
+
+// Every value that ends up in SQL goes through these two helpers, so user
+// input is always a quoted literal and never a fragment of the statement. The
+// library takes the condition as text only, there is nothing to bind to.
+fn sql_string(v: &str) -> String {
+    format!("'{}'", v.replace('\'', "''"))
+}
+
+fn sql_like(v: &str) -> String {
+    let escaped = v
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_");
+    format!("{} ESCAPE '\\'", sql_string(&format!("%{}%", escaped)))
+}
+
+#[derive(Debug)]
+pub struct QueryError {
+    pub message: String,
+    pub start: usize,
+    pub len: usize,
+}
+
+impl QueryError {
+    fn new<S: Into<String>>(message: S, start: usize, len: usize) -> Self {
+        Self {
+            message: message.into(),
+            start,
+            len: len.max(1),
+        }
+    }
+
+    // Query with a caret line underneath the offending token.
+    pub fn render(&self, query: &str) -> String {
+        format!(
+            "{}\n{}{} {}",
+            query,
+            " ".repeat(self.start),
+            "^".repeat(self.len),
+            self.message
+        )
+    }
+
+    pub fn in_query(self, query: &str) -> InvalidQuery {
+        InvalidQuery {
+            query: query.to_string(),
+            error: self,
+        }
+    }
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at column {}.", self.message, self.start + 1)
+    }
+}
+
+impl Error for QueryError {}
+
+// A query error along with its query, so the report shows the caret line.
+// Debug gives the same, as that is what failed commands are reported with.
+pub struct InvalidQuery {
+    query: String,
+    error: QueryError,
+}
+
+impl fmt::Display for InvalidQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Cannot parse the query:\n{}",
+            self.error.render(&self.query)
+        )
+    }
+}
+
+impl fmt::Debug for InvalidQuery {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl Error for InvalidQuery {}
+
+// Positions are counted in chars so the caret lines up with the query.
+struct Token {
+    raw: String,
+    start: usize,
+}
+
+impl Token {
+    fn len(&self) -> usize {
+        self.raw.chars().count()
+    }
+}
+
+fn tokenize(query: &str) -> Result<Vec<Token>, QueryError> {
+    let mut tokens = vec![];
+    let mut current: Option<Token> = None;
+    let mut quote_start: Option<usize> = None;
+    for (pos, c) in query.chars().enumerate() {
+        if c.is_whitespace() && quote_start.is_none() {
+            if let Some(token) = current.take() {
+                tokens.push(token);
+            }
+            continue;
+        }
+        if c == '"' {
+            quote_start = match quote_start {
+                Some(_) => None,
+                None => Some(pos),
+            };
+        }
+        current
+            .get_or_insert_with(|| Token {
+                raw: String::new(),
+                start: pos,
+            })
+            .raw
+            .push(c);
+    }
+    if let Some(start) = quote_start {
+        return Err(QueryError::new(
+            "Unterminated quote",
+            start,
+            query.chars().count() - start,
+        ));
+    }
+    if let Some(token) = current {
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+fn unquote(v: &str) -> String {
+    v.chars().filter(|c| *c != '"').collect()
+}
+
+#[derive(Clone, Copy)]
+enum Compare {
+    Eq,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Compare {
+    fn as_sql(&self) -> &'static str {
+        match self {
+            Compare::Eq => "=",
+            Compare::Lt => "<",
+            Compare::Le => "<=",
+            Compare::Gt => ">",
+            Compare::Ge => ">=",
+        }
+    }
+}
+
+enum Term {
+    Kind(MediaType),
+    Tag(Uuid),
+    Series(Uuid),
+    Size(Compare, u64),
+    Id(Compare, u64),
+    Added(Option<DateTime<Local>>, Option<DateTime<Local>>),
+    Captured(Option<DateTime<Local>>, Option<DateTime<Local>>),
+    Name(String),
+    Hash(String),
+    Text(String),
+}
+
+struct Clause {
+    term: Term,
+    negated: bool,
+}
+
+fn parse_size(v: &str) -> Option<u64> {
+    let v = v.trim().to_ascii_lowercase();
+    let split = v
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(v.len());
+    let (number, unit) = v.split_at(split);
+    let number: f64 = number.parse().ok()?;
+    let unit: u64 = match unit {
+        "" | "b" => 1,
+        "k" | "kb" | "kib" => 1 << 10,
+        "m" | "mb" | "mib" => 1 << 20,
+        "g" | "gb" | "gib" => 1 << 30,
+        "t" | "tb" | "tib" => 1 << 40,
+        _ => return None,
+    };
+    Some((number * unit as f64) as u64)
+}
+
+fn is_hex(v: &str) -> bool {
+    !v.is_empty() && v.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+// Either end of a range may be open.
+fn in_range(
+    time: DateTime<Local>,
+    from: Option<DateTime<Local>>,
+    to: Option<DateTime<Local>>,
+) -> bool {
+    from.iter().all(|t| time >= *t) && to.iter().all(|t| time < *t)
+}
+
+// A date like 2021, 2021-03 or 2021-03-15 covers the whole year, month or day.
+fn parse_period(v: &str) -> Option<(DateTime<Local>, DateTime<Local>)> {
+    let parts = v
+        .split('-')
+        .map(|p| p.parse::<u32>().ok())
+        .collect::<Option<Vec<u32>>>()?;
+    let (start, end) = match parts.as_slice() {
+        [y] => (
+            NaiveDate::from_ymd_opt(*y as i32, 1, 1)?,
+            NaiveDate::from_ymd_opt(*y as i32 + 1, 1, 1)?,
+        ),
+        [y, m] => {
+            let start = NaiveDate::from_ymd_opt(*y as i32, *m, 1)?;
+            let end = if *m == 12 {
+                NaiveDate::from_ymd_opt(start.year() + 1, 1, 1)?
+            } else {
+                NaiveDate::from_ymd_opt(start.year(), *m + 1, 1)?
+            };
+            (start, end)
+        }
+        [y, m, d] => {
+            let start = NaiveDate::from_ymd_opt(*y as i32, *m, *d)?;
+            (start, start.succ_opt()?)
+        }
+        _ => return None,
+    };
+    let to_local = |d: NaiveDate| {
+        Local
+            .from_local_datetime(&d.and_hms_opt(0, 0, 0)?)
+            .earliest()
+    };
+    Some((to_local(start)?, to_local(end)?))
+}
+
+pub struct Query {
+    clauses: Vec<Clause>,
+}
+
+impl Query {
+    pub fn parse(lib: &Library, query: &str) -> Result<Self, QueryError> {
+        Self::parse_with(query, |kind, name| resolve_set(lib, kind, name))
+    }
+
+    // Media whose hash starts with `hash`, so a whole hash finds at most one.
+    pub fn hash(hash: &str) -> Option<Self> {
+        if !is_hex(hash) {
+            return None;
+        }
+        Some(Self {
+            clauses: vec![Clause {
+                term: Term::Hash(hash.to_ascii_lowercase()),
+                negated: false,
+            }],
+        })
+    }
+
+    // `resolve` finds the tag or series a clause names.
+    fn parse_with<F>(query: &str, resolve: F) -> Result<Self, QueryError>
+    where
+        F: Fn(MediaSetType, &str) -> Result<Uuid, Box<dyn Error>>,
+    {
+        let mut clauses = vec![];
+        for token in tokenize(query)? {
+            clauses.push(Self::parse_clause(&resolve, &token)?);
+        }
+        Ok(Self { clauses })
+    }
+
+    fn parse_clause<F>(resolve: &F, token: &Token) -> Result<Clause, QueryError>
+    where
+        F: Fn(MediaSetType, &str) -> Result<Uuid, Box<dyn Error>>,
+    {
+        let (negated, raw, start) = if token.raw.len() > 1 && token.raw.starts_with('-') {
+            (true, &token.raw[1..], token.start + 1)
+        } else {
+            (false, token.raw.as_str(), token.start)
+        };
+        let op_pos = raw.find([':', '<', '>', '=']);
+        let field = op_pos
+            .map(|p| &raw[0..p])
+            .filter(|f| !f.is_empty() && f.chars().all(|c| c.is_ascii_alphabetic()));
+        let (field, op_pos) = match (field, op_pos) {
+            (Some(f), Some(p)) => (f.to_ascii_lowercase(), p),
+            _ => {
+                let text = unquote(raw);
+                if text.is_empty() {
+                    return Err(QueryError::new("Empty text", start, token.len()));
+                }
+                return Ok(Clause {
+                    term: Term::Text(text),
+                    negated,
+                });
+            }
+        };
+        let rest = &raw[op_pos..];
+        // `size:>2MB` reads the same as `size>2MB`.
+        let colon = if rest.starts_with(':') && rest[1..].starts_with(['<', '>']) {
+            1
+        } else {
+            0
+        };
+        let (op, op_len) = if rest[colon..].starts_with(">=") {
+            (Compare::Ge, 2)
+        } else if rest[colon..].starts_with("<=") {
+            (Compare::Le, 2)
+        } else if rest[colon..].starts_with('>') {
+            (Compare::Gt, 1)
+        } else if rest[colon..].starts_with('<') {
+            (Compare::Lt, 1)
+        } else {
+            (Compare::Eq, 1)
+        };
+        let op_len = op_len + colon;
+        let value = unquote(&rest[op_len..]);
+        let field_len = field.chars().count();
+        let value_start = start + field_len + op_len;
+        let value_len = rest[op_len..].chars().count();
+        let value_error = |message: String| QueryError::new(message, value_start, value_len);
+        if value.is_empty() {
+            return Err(value_error(format!("Missing value for {}", field)));
+        }
+        let ordered = matches!(
+            field.as_str(),
+            "size" | "id" | "added" | "date" | "captured" | "taken"
+        );
+        if !ordered && !matches!(op, Compare::Eq) {
+            return Err(QueryError::new(
+                format!("{} cannot be compared by order", field),
+                start + field_len,
+                op_len,
+            ));
+        }
+        let term = match field.as_str() {
+            "kind" | "type" => Term::Kind(
+                is_valid_media_type(&value)
+                    .and_then(|_| MediaType::from_str(value.trim()).map_err(|e| e.to_string()))
+                    .map_err(|_| value_error(format!("{} is not a media type", value)))?,
+            ),
+            "tag" => Term::Tag(
+                resolve(MediaSetType::Tag, &value).map_err(|e| value_error(e.to_string()))?,
+            ),
+            "series" => Term::Series(
+                resolve(MediaSetType::Series, &value).map_err(|e| value_error(e.to_string()))?,
+            ),
+            "size" => Term::Size(
+                op,
+                parse_size(&value)
+                    .ok_or_else(|| value_error(format!("{} is not a size like 2MB", value)))?,
+            ),
+            "id" => Term::Id(
+                op,
+                value
+                    .parse()
+                    .map_err(|_| value_error(format!("{} is not a media ID", value)))?,
+            ),
+            "added" | "date" | "captured" | "taken" => {
+                let period = |v: &str| {
+                    parse_period(v)
+                        .ok_or_else(|| value_error(format!("{} is not a date like 2021-03-15", v)))
+                };
+                let (from, to) = match (op, value.find("..")) {
+                    (Compare::Eq, Some(p)) => {
+                        let (from, to) = (&value[0..p], &value[p + 2..]);
+                        (
+                            if from.is_empty() {
+                                None
+                            } else {
+                                Some(period(from)?.0)
+                            },
+                            if to.is_empty() {
+                                None
+                            } else {
+                                Some(period(to)?.1)
+                            },
+                        )
+                    }
+                    (op, _) => {
+                        let (from, to) = period(&value)?;
+                        match op {
+                            Compare::Eq => (Some(from), Some(to)),
+                            Compare::Gt => (Some(to), None),
+                            Compare::Ge => (Some(from), None),
+                            Compare::Lt => (None, Some(from)),
+                            Compare::Le => (None, Some(to)),
+                        }
+                    }
+                };
+                if matches!(field.as_str(), "captured" | "taken") {
+                    Term::Captured(from, to)
+                } else {
+                    Term::Added(from, to)
+                }
+            }
+            "name" | "filename" => Term::Name(value),
+            "hash" => {
+                if !is_hex(&value) {
+                    return Err(value_error(format!("{} is not a hex hash", value)));
+                }
+                Term::Hash(value.to_ascii_lowercase())
+            }
+            _ => {
+                return Err(QueryError::new(
+                    format!("Unknown field {}", field),
+                    start,
+                    field_len,
+                ))
+            }
+        };
+        Ok(Clause { term, negated })
+    }
+
+    // The part of the query the database can answer.
+    fn to_sql(&self) -> String {
+        let conditions: Vec<String> = self
+            .clauses
+            .iter()
+            .filter_map(|clause| {
+                let condition = match &clause.term {
+                    Term::Size(op, v) => format!("filesize {} {}", op.as_sql(), v),
+                    Term::Id(op, v) => format!("id {} {}", op.as_sql(), v),
+                    Term::Name(v) => format!("filename LIKE {}", sql_like(v)),
+                    Term::Hash(v) => format!("hash LIKE {}", sql_string(&format!("{}%", v))),
+                    // Without a caption or comment the column is NULL, and so would
+                    // be the negated condition.
+                    Term::Text(v) => format!(
+                        "(IFNULL(filename, '') LIKE {0} OR IFNULL(caption, '') LIKE {0} OR IFNULL(comment, '') LIKE {0})",
+                        sql_like(v)
+                    ),
+                    _ => return None,
+                };
+                Some(if clause.negated {
+                    format!("NOT ({})", condition)
+                } else {
+                    condition
+                })
+            })
+            .collect();
+        if conditions.is_empty() {
+            "1 = 1".to_string()
+        } else {
+            conditions.join(" AND ")
+        }
+    }
+
+    // The part of the query which has to be checked against loaded media.
+    fn matches(&self, media: &Media) -> bool {
+        self.clauses.iter().all(|clause| {
+            let matched = match &clause.term {
+                Term::Kind(k) => &media.kind == k,
+                Term::Tag(u) => media.tag.contains(u),
+                Term::Series(u) => media.series.contains(u),
+                Term::Added(from, to) => in_range(media.time_add, *from, *to),
+                // Media without a capture time are never in a range.
+                Term::Captured(from, to) => {
+                    matches!(capture_time(media), Some(time) if in_range(time, *from, *to))
+                }
+                _ => return true,
+            };
+            matched != clause.negated
+        })
+    }
+
+    pub fn run(&self, lib: &Library) -> Result<Vec<Media>, LibError> {
+        let mut media = vec![];
+        for id in lib.query_media(&self.to_sql())? {
+            let m = lib.get_media(id)?;
+            if self.matches(&m) {
+                media.push(m);
+            }
+        }
+        Ok(media)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(query: &str) -> Result<Query, QueryError> {
+        Query::parse_with(query, |_, name| match name {
+            "cats" => Ok(Uuid::nil()),
+            _ => Err(format!("Tag {} is not existed.", name).into()),
+        })
+    }
+
+    fn sql(query: &str) -> String {
+        parse(query).unwrap().to_sql()
+    }
+
+    fn day(t: DateTime<Local>) -> String {
+        t.format("%Y-%m-%d").to_string()
+    }
+
+    fn error(query: &str) -> QueryError {
+        match parse(query) {
+            Ok(_) => panic!("{} is accepted", query),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn empty_query_matches_everything() {
+        assert_eq!(sql(""), "1 = 1");
+        assert_eq!(sql("   "), "1 = 1");
+    }
+
+    #[test]
+    fn operators() {
+        assert_eq!(sql("id=3"), "id = 3");
+        assert_eq!(sql("id:3"), "id = 3");
+        assert_eq!(sql("size>=1k id<5"), "filesize >= 1024 AND id < 5");
+        assert_eq!(sql("size<=1.5kb"), "filesize <= 1536");
+        assert_eq!(sql("size:>2MB"), "filesize > 2097152");
+        assert_eq!(sql("SIZE:<1gib"), "filesize < 1073741824");
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("12"), Some(12));
+        assert_eq!(parse_size("2MB"), Some(2 << 20));
+        assert_eq!(parse_size("1T"), Some(1 << 40));
+        assert_eq!(parse_size("2 mb"), None);
+        assert_eq!(parse_size("MB"), None);
+        assert_eq!(parse_size("2pb"), None);
+    }
+
+    #[test]
+    fn negation() {
+        assert_eq!(sql("-id:3"), "NOT (id = 3)");
+        assert_eq!(
+            sql("-cat"),
+            "NOT ((IFNULL(filename, '') LIKE '%cat%' ESCAPE '\\' OR IFNULL(caption, '') LIKE '%cat%' ESCAPE '\\' OR IFNULL(comment, '') LIKE '%cat%' ESCAPE '\\'))"
+        );
+        // A lone dash is text, not an empty negation.
+        assert!(sql("-").contains("LIKE '%-%'"));
+    }
+
+    #[test]
+    fn quoting() {
+        assert_eq!(
+            sql("name:\"it's 50%_off\""),
+            "filename LIKE '%it''s 50\\%\\_off%' ESCAPE '\\'"
+        );
+        assert_eq!(sql("hash:ABC"), "hash LIKE 'abc%'");
+        assert_eq!(
+            sql("name:\"a b\" id:1"),
+            "filename LIKE '%a b%' ESCAPE '\\' AND id = 1"
+        );
+    }
+
+    #[test]
+    fn terms_outside_sql() {
+        let query = parse("kind:image tag:cats -series:cats added:2021-03").unwrap();
+        assert_eq!(query.to_sql(), "1 = 1");
+        assert!(matches!(
+            query.clauses[0].term,
+            Term::Kind(MediaType::Image)
+        ));
+        assert!(matches!(query.clauses[1].term, Term::Tag(_)));
+        assert!(query.clauses[2].negated);
+        match query.clauses[3].term {
+            Term::Added(Some(from), Some(to)) => {
+                assert_eq!(day(from), "2021-03-01");
+                assert_eq!(day(to), "2021-04-01");
+            }
+            _ => panic!("added is not a closed range"),
+        }
+    }
+
+    #[test]
+    fn ranges() {
+        let (from, to) = parse_period("2021-03").unwrap();
+        assert!(in_range(from, Some(from), Some(to)));
+        assert!(!in_range(to, Some(from), Some(to)));
+        assert!(in_range(to, None, None));
+        assert!(in_range(to, Some(from), None));
+        assert!(!in_range(from, None, Some(from)));
+    }
+
+    #[test]
+    fn periods() {
+        let (from, to) = parse_period("2020-12").unwrap();
+        assert_eq!(day(from), "2020-12-01");
+        assert_eq!(day(to), "2021-01-01");
+        assert!(parse_period("2021-02-30").is_none());
+        assert!(parse_period("yesterday").is_none());
+        match parse("added>2021").unwrap().clauses[0].term {
+            Term::Added(Some(from), None) => {
+                assert_eq!(day(from), "2022-01-01")
+            }
+            _ => panic!("added>2021 is not open ended"),
+        }
+        match parse("taken:..2021").unwrap().clauses[0].term {
+            Term::Captured(None, Some(to)) => {
+                assert_eq!(day(to), "2022-01-01")
+            }
+            _ => panic!("taken:..2021 is not open ended"),
+        }
+    }
+
+    #[test]
+    fn kinds_are_checked() {
+        assert!(matches!(
+            parse("kind:other").unwrap().clauses[0].term,
+            Term::Kind(MediaType::Other)
+        ));
+        let e = error("kind:picture");
+        assert_eq!(e.message, "picture is not a media type");
+        assert_eq!((e.start, e.len), (5, 7));
+    }
+
+    #[test]
+    fn bad_input() {
+        assert_eq!(error("color:red").message, "Unknown field color");
+        assert_eq!(error("id:x").message, "x is not a media ID");
+        assert_eq!(error("size:big").message, "big is not a size like 2MB");
+        assert_eq!(error("hash:xyz").message, "xyz is not a hex hash");
+        assert_eq!(error("tag:dogs").message, "Tag dogs is not existed.");
+        assert_eq!(error("name>a").message, "name cannot be compared by order");
+        assert_eq!(error("id:").message, "Missing value for id");
+        assert_eq!(error("\"\"").message, "Empty text");
+        let e = error("id:1 \"open");
+        assert_eq!(e.message, "Unterminated quote");
+        assert_eq!((e.start, e.len), (5, 5));
+        assert_eq!(
+            e.render("id:1 \"open"),
+            "id:1 \"open\n     ^^^^^ Unterminated quote"
+        );
+        assert_eq!(
+            format!("{:?}", error("id:x").in_query("id:x")),
+            "Cannot parse the query:\nid:x\n   ^ x is not a media ID"
+        );
+    }
+
+    #[test]
+    fn hash_lookup() {
+        assert_eq!(Query::hash("ABC01").unwrap().to_sql(), "hash LIKE 'abc01%'");
+        assert!(Query::hash("").is_none());
+        assert!(Query::hash("ab' OR '1' = '1").is_none());
+    }
+}
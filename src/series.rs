@@ -0,0 +1,343 @@
+use std::error::Error;
+
+use console::Style;
+use dialoguer::{theme::ColorfulTheme, Confirm};
+use shiromana_rs::library::{Library, MediaSetType};
+use shiromana_rs::media::Media;
+use shiromana_rs::misc::Uuid;
+
+use crate::command::{
+    find_media, print_media, resolve_set, DECO_BRANCH, DECO_LEFT_PAR_M, DECO_RIGHT_PAR_M,
+    STYLE_ERROR, STYLE_FIELD_NAME, STYLE_FIELD_VALUE,
+};
+use crate::output::{MembershipRecord, Output, Record, SetRecord};
+use crate::{
+    AppConfig, Series, SeriesCommand, SeriesDelete, SeriesMerge, SeriesMove, SeriesRemove,
+    SeriesRename, SeriesShow,
+};
+
+fn confirm(prompt: String) -> Result<bool, Box<dyn Error>> {
+    let theme = ColorfulTheme {
+        values_style: Style::new().yellow().dim(),
+        ..ColorfulTheme::default()
+    };
+    Ok(Confirm::with_theme(&theme)
+        .default(false)
+        .with_prompt(prompt)
+        .interact()?)
+}
+
+fn find_one_media(lib: &Library, query_string: &str) -> Result<Media, Box<dyn Error>> {
+    match find_media(lib, query_string).as_slice() {
+        [] => Err(format!("Cannot acquire any media via: {}", query_string).into()),
+        [m] => Ok(m.clone()),
+        v => Err(format!(
+            "{} media are found via {}, use the Media ID instead.",
+            v.len(),
+            query_string
+        )
+        .into()),
+    }
+}
+
+// Number of leading members which are already where they should be.
+fn unchanged_prefix(current: &[u64], order: &[u64]) -> usize {
+    current
+        .iter()
+        .zip(order.iter())
+        .take_while(|(a, b)| a == b)
+        .count()
+}
+
+// Members after the first `kept` are taken out and `tail` is appended instead.
+fn replace_tail(
+    lib: &mut Library,
+    uuid: &Uuid,
+    kept: usize,
+    tail: &[u64],
+) -> Result<(), Box<dyn Error>> {
+    let members = lib.get_set(MediaSetType::Series, uuid)?.media;
+    for id in members.iter().skip(kept) {
+        lib.remove_from_set(MediaSetType::Series, *id, uuid)?;
+    }
+    for id in tail.iter() {
+        lib.add_to_set(MediaSetType::Series, *id, uuid, None, false)?;
+    }
+    Ok(())
+}
+
+// There is no way to move a single member, only to append one, so every member
+// from the first one out of place on is taken out and put back in the wanted
+// order, the same way `add --sorted` fills a series. When that fails midway the
+// original order is put back.
+fn reorder_series(lib: &mut Library, uuid: &Uuid, order: &[u64]) -> Result<(), Box<dyn Error>> {
+    let current = lib.get_set(MediaSetType::Series, uuid)?.media;
+    let kept = unchanged_prefix(&current, order);
+    if let Err(e) = replace_tail(lib, uuid, kept, &order[kept..]) {
+        return Err(match replace_tail(lib, uuid, kept, &current[kept..]) {
+            Ok(_) => e,
+            Err(v) => format!(
+                "{}, and the original order cannot be restored due to {}",
+                e, v
+            )
+            .into(),
+        });
+    }
+    Ok(())
+}
+
+fn membership(action: &str, uuid: &Uuid, media: Vec<u64>) -> Record {
+    Record::Membership(MembershipRecord {
+        kind: "series".to_string(),
+        action: action.to_string(),
+        uuid: uuid.to_string(),
+        media,
+    })
+}
+
+fn do_series_list(lib: &Library, out: &mut Output) -> Result<(), Box<dyn Error>> {
+    let mut series = lib
+        .query_set(MediaSetType::Series, "1 = 1")?
+        .iter()
+        .map(|uuid| lib.get_set(MediaSetType::Series, uuid))
+        .collect::<Result<Vec<_>, _>>()?;
+    series.sort_by(|a, b| a.name.cmp(&b.name));
+    for s in series.iter() {
+        out.emit(Record::SetInfo(s.into()), || {
+            println!(
+                "{} {}{}{} {}",
+                STYLE_FIELD_NAME.apply_to(&s.name),
+                *DECO_LEFT_PAR_M,
+                STYLE_FIELD_VALUE.apply_to(s.media.len()),
+                *DECO_RIGHT_PAR_M,
+                STYLE_FIELD_VALUE.apply_to(s.uuid),
+            );
+            if let Some(v) = &s.comment {
+                println!("    {} {}", *DECO_BRANCH, STYLE_FIELD_VALUE.apply_to(v));
+            }
+        });
+    }
+    Ok(())
+}
+
+fn do_series_show(opt: SeriesShow, lib: &Library, out: &mut Output) -> Result<(), Box<dyn Error>> {
+    let uuid = resolve_set(lib, MediaSetType::Series, &opt.series)?;
+    let series = lib.get_set(MediaSetType::Series, &uuid)?;
+    out.emit(Record::SetInfo((&series).into()), || {
+        println!(
+            "{}: {} {}{}{}",
+            STYLE_FIELD_NAME.apply_to("Series"),
+            STYLE_FIELD_VALUE.apply_to(&series.name),
+            *DECO_LEFT_PAR_M,
+            STYLE_FIELD_VALUE.apply_to(series.uuid),
+            *DECO_RIGHT_PAR_M,
+        );
+        if let Some(v) = &series.comment {
+            println!(
+                "{}: {}",
+                STYLE_FIELD_NAME.apply_to("Comment"),
+                STYLE_FIELD_VALUE.apply_to(v)
+            );
+        }
+    });
+    for (i, id) in series.media.iter().enumerate() {
+        let m = lib.get_media(*id)?;
+        out.emit(Record::Media(Box::new((&m).into())), || {
+            print!("{:>4}. ", STYLE_FIELD_NAME.apply_to(i + 1));
+            print_media(&m, false, None);
+        });
+    }
+    Ok(())
+}
+
+fn do_series_move(
+    opt: SeriesMove,
+    lib: &mut Library,
+    out: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    let uuid = resolve_set(lib, MediaSetType::Series, &opt.series)?;
+    let media = find_one_media(lib, &opt.media)?;
+    let mut order = lib.get_set(MediaSetType::Series, &uuid)?.media;
+    let from = match order.iter().position(|v| *v == media.id) {
+        Some(v) => v,
+        None => return Err(format!("Media {} is not in the series.", media.id).into()),
+    };
+    if opt.position == 0 || opt.position > order.len() {
+        return Err(format!("Position should be in 1 to {}.", order.len()).into());
+    }
+    order.remove(from);
+    order.insert(opt.position - 1, media.id);
+    reorder_series(lib, &uuid, &order)?;
+    out.emit(membership("move", &uuid, order), || {
+        println!(
+            "Successfully Moved Media {} from {} to {}.",
+            media.id,
+            from + 1,
+            opt.position
+        )
+    });
+    Ok(())
+}
+
+fn do_series_remove(
+    opt: SeriesRemove,
+    lib: &mut Library,
+    out: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    let uuid = resolve_set(lib, MediaSetType::Series, &opt.series)?;
+    let mut ids = vec![];
+    for query_string in opt.media.iter() {
+        let media = find_one_media(lib, query_string)?;
+        if !media.series.contains(&uuid) {
+            out.error(format!("Media {} is not in the series.", media.id), || {
+                println!(
+                    "{}: {}",
+                    STYLE_ERROR.apply_to("Media is not in the series"),
+                    STYLE_FIELD_VALUE.apply_to(media.id)
+                )
+            });
+            continue;
+        }
+        lib.remove_from_set(MediaSetType::Series, media.id, &uuid)?;
+        ids.push(media.id);
+    }
+    out.emit(membership("remove", &uuid, ids.clone()), || {
+        println!(
+            "Successfully Removed {} Medias from Series {}.",
+            ids.len(),
+            uuid
+        )
+    });
+    Ok(())
+}
+
+fn do_series_rename(
+    opt: SeriesRename,
+    lib: &mut Library,
+    out: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    let uuid = resolve_set(lib, MediaSetType::Series, &opt.series)?;
+    if let (Some(v), _) = lib.get_set_by_name(opt.name.clone())? {
+        return Err(format!("Series {} is already existed as {}.", opt.name, v).into());
+    }
+    lib.update_set(MediaSetType::Series, &uuid, Some(opt.name.clone()), None)?;
+    out.emit(
+        Record::Set(SetRecord::new(
+            MediaSetType::Series,
+            opt.name.clone(),
+            uuid,
+            false,
+        )),
+        || {
+            println!(
+                "{}: {}{}{} {}{}{}",
+                STYLE_FIELD_NAME.apply_to("Successfully renamed series"),
+                *DECO_LEFT_PAR_M,
+                STYLE_FIELD_NAME.apply_to(&opt.name),
+                *DECO_RIGHT_PAR_M,
+                *DECO_LEFT_PAR_M,
+                STYLE_FIELD_NAME.apply_to(uuid),
+                *DECO_RIGHT_PAR_M,
+            )
+        },
+    );
+    Ok(())
+}
+
+// Members of `from` are appended to `into` in their order, then `from` is deleted.
+fn do_series_merge(
+    opt: SeriesMerge,
+    lib: &mut Library,
+    out: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    let from = resolve_set(lib, MediaSetType::Series, &opt.from)?;
+    let into = resolve_set(lib, MediaSetType::Series, &opt.into)?;
+    if from == into {
+        return Err("Cannot merge a series into itself.".into());
+    }
+    let from_media = lib.get_set(MediaSetType::Series, &from)?.media;
+    let into_media = lib.get_set(MediaSetType::Series, &into)?.media;
+    if !opt.yes
+        && !confirm(format!(
+            "Merge {} media of {} into {} and delete {}?",
+            from_media.len(),
+            opt.from,
+            opt.into,
+            opt.from
+        ))?
+    {
+        return Ok(());
+    }
+    let mut moved = vec![];
+    for id in from_media.iter() {
+        if !into_media.contains(id) {
+            lib.add_to_set(MediaSetType::Series, *id, &into, None, false)?;
+            moved.push(*id);
+        }
+    }
+    lib.remove_set(MediaSetType::Series, &from)?;
+    out.emit(membership("merge", &into, moved.clone()), || {
+        println!(
+            "Successfully Merged {} Medias into Series {}.",
+            moved.len(),
+            into
+        )
+    });
+    out.emit(membership("delete", &from, from_media), || {
+        println!("Successfully Deleted Series {}.", from)
+    });
+    Ok(())
+}
+
+fn do_series_delete(
+    opt: SeriesDelete,
+    lib: &mut Library,
+    out: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    let uuid = resolve_set(lib, MediaSetType::Series, &opt.series)?;
+    let media = lib.get_set(MediaSetType::Series, &uuid)?.media;
+    if !opt.yes
+        && !confirm(format!(
+            "Delete series {} with {} media? The media themselves are kept.",
+            opt.series,
+            media.len()
+        ))?
+    {
+        return Ok(());
+    }
+    lib.remove_set(MediaSetType::Series, &uuid)?;
+    out.emit(membership("delete", &uuid, media), || {
+        println!("Successfully Deleted Series {}.", uuid)
+    });
+    Ok(())
+}
+
+pub fn do_series(
+    opt: Series,
+    _cfg: AppConfig,
+    lib: &mut Library,
+    out: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    match opt.subcmd {
+        SeriesCommand::List => do_series_list(lib, out),
+        SeriesCommand::Show(opt) => do_series_show(opt, lib, out),
+        SeriesCommand::Move(opt) => do_series_move(opt, lib, out),
+        SeriesCommand::Remove(opt) => do_series_remove(opt, lib, out),
+        SeriesCommand::Rename(opt) => do_series_rename(opt, lib, out),
+        SeriesCommand::Merge(opt) => do_series_merge(opt, lib, out),
+        SeriesCommand::Delete(opt) => do_series_delete(opt, lib, out),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn prefix() {
+        assert_eq!(unchanged_prefix(&[1, 2, 3, 4], &[1, 2, 4, 3]), 2);
+        assert_eq!(unchanged_prefix(&[1, 2, 3], &[3, 1, 2]), 0);
+        assert_eq!(unchanged_prefix(&[1, 2, 3], &[1, 2, 3]), 3);
+        assert_eq!(unchanged_prefix(&[], &[]), 0);
+    }
+}
@@ -0,0 +1,171 @@
+use std::error::Error;
+
+use shiromana_rs::library::{Library, MediaSetType};
+use shiromana_rs::misc::Uuid;
+
+use crate::command::{
+    create_tag, find_media, resolve_set, set_name, DECO_BRANCH, DECO_LEFT_PAR_M, DECO_RIGHT_PAR_M,
+    STYLE_ERROR, STYLE_FIELD_NAME, STYLE_FIELD_VALUE,
+};
+use crate::output::{MembershipRecord, Output, Record, SetRecord};
+use crate::{AppConfig, Tag, TagAssign, TagCommand, TagList};
+
+fn resolve_tag(
+    lib: &mut Library,
+    tag: &str,
+    create: bool,
+    out: &mut Output,
+) -> Result<Uuid, Box<dyn Error>> {
+    match resolve_set(lib, MediaSetType::Tag, tag) {
+        Ok(uuid) => Ok(uuid),
+        Err(e) if !create => Err(e),
+        Err(_) => {
+            let tags = create_tag(lib, tag, None, true)?;
+            for (name, uuid, created) in tags.iter().filter(|v| v.2) {
+                out.emit(
+                    Record::Set(SetRecord::new(
+                        MediaSetType::Tag,
+                        name.clone(),
+                        *uuid,
+                        *created,
+                    )),
+                    || {
+                        println!(
+                            "{}: {}{}{} {}{}{}",
+                            STYLE_FIELD_NAME.apply_to("Successfully created tag"),
+                            *DECO_LEFT_PAR_M,
+                            STYLE_FIELD_NAME.apply_to(name),
+                            *DECO_RIGHT_PAR_M,
+                            *DECO_LEFT_PAR_M,
+                            STYLE_FIELD_NAME.apply_to(uuid),
+                            *DECO_RIGHT_PAR_M,
+                        )
+                    },
+                );
+            }
+            Ok(tags.last().unwrap().1)
+        }
+    }
+}
+
+fn do_tag_assign(
+    opt: TagAssign,
+    remove: bool,
+    lib: &mut Library,
+    out: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    let uuid = resolve_tag(lib, &opt.tag, opt.create && !remove, out)?;
+    let tag_name = set_name(lib, MediaSetType::Tag, &uuid);
+    let mut ids = vec![];
+    for query_string in opt.media.iter() {
+        let media = find_media(lib, query_string);
+        if media.is_empty() {
+            out.error(
+                format!("Cannot acquire any media via: {}", query_string),
+                || {
+                    println!(
+                        "{} {}",
+                        STYLE_ERROR.apply_to("Cannot acquire any media via: "),
+                        STYLE_FIELD_VALUE.apply_to(query_string)
+                    )
+                },
+            );
+        }
+        for m in media {
+            // Nothing to do when the media is already in the wanted state.
+            if m.tag.contains(&uuid) != remove {
+                continue;
+            }
+            let result = if remove {
+                lib.remove_from_set(MediaSetType::Tag, m.id, &uuid)
+            } else {
+                lib.add_to_set(MediaSetType::Tag, m.id, &uuid, None, true)
+            };
+            match result {
+                Ok(_) => ids.push(m.id),
+                Err(e) => out.error(format!("Error when tagging media {}: {}", m.id, e), || {
+                    println!(
+                        "{}: {} {}{}{}, {}: {}",
+                        STYLE_ERROR.apply_to("Error when tagging media"),
+                        STYLE_FIELD_VALUE.apply_to(&m.filename),
+                        *DECO_LEFT_PAR_M,
+                        STYLE_FIELD_VALUE.apply_to(m.id),
+                        *DECO_RIGHT_PAR_M,
+                        STYLE_ERROR.apply_to("Due to"),
+                        STYLE_FIELD_VALUE.apply_to(e.to_string())
+                    )
+                }),
+            }
+        }
+    }
+    out.emit(
+        Record::Membership(MembershipRecord {
+            kind: "tag".to_string(),
+            action: if remove { "remove" } else { "add" }.to_string(),
+            uuid: uuid.to_string(),
+            media: ids.clone(),
+        }),
+        || {
+            println!(
+                "Successfully {} {} Medias {} Tag {}.",
+                if remove { "Removed" } else { "Added" },
+                ids.len(),
+                if remove { "from" } else { "to" },
+                tag_name
+            )
+        },
+    );
+    Ok(())
+}
+
+fn do_tag_list(opt: TagList, lib: &Library, out: &mut Output) -> Result<(), Box<dyn Error>> {
+    let uuids = match &opt.media {
+        Some(query_string) => match find_media(lib, query_string).as_slice() {
+            [] => return Err(format!("Cannot acquire any media via: {}", query_string).into()),
+            [m] => m.tag.clone(),
+            v => {
+                return Err(format!(
+                    "{} media are found via {}, use the Media ID instead.",
+                    v.len(),
+                    query_string
+                )
+                .into())
+            }
+        },
+        None => lib.query_set(MediaSetType::Tag, "1 = 1")?,
+    };
+    let mut tags = uuids
+        .iter()
+        .map(|uuid| lib.get_set(MediaSetType::Tag, uuid))
+        .collect::<Result<Vec<_>, _>>()?;
+    tags.sort_by(|a, b| a.name.cmp(&b.name));
+    for tag in tags.iter() {
+        out.emit(Record::SetInfo(tag.into()), || {
+            println!(
+                "{} {}{}{} {}",
+                STYLE_FIELD_NAME.apply_to(&tag.name),
+                *DECO_LEFT_PAR_M,
+                STYLE_FIELD_VALUE.apply_to(tag.media.len()),
+                *DECO_RIGHT_PAR_M,
+                STYLE_FIELD_VALUE.apply_to(tag.uuid),
+            );
+            if let Some(v) = &tag.comment {
+                println!("    {} {}", *DECO_BRANCH, STYLE_FIELD_VALUE.apply_to(v));
+            }
+        });
+    }
+    Ok(())
+}
+
+pub fn do_tag(
+    opt: Tag,
+    _cfg: AppConfig,
+    lib: &mut Library,
+    out: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    match opt.subcmd {
+        TagCommand::Add(opt) => do_tag_assign(opt, false, lib, out),
+        TagCommand::Remove(opt) => do_tag_assign(opt, true, lib, out),
+        TagCommand::List(opt) => do_tag_list(opt, lib, out),
+    }
+}
@@ -0,0 +1,326 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use image::codecs::jpeg::JpegEncoder;
+use image::{ColorType, DynamicImage, GenericImageView};
+use indicatif::{ProgressBar, ProgressStyle};
+use shiromana_rs::library::Library;
+use shiromana_rs::media::{Media, MediaDetail, MediaType};
+
+use crate::command::{find_media, media_file_path, STYLE_ERROR, STYLE_FIELD_VALUE};
+use crate::output::{Output, Record, ThumbRecord};
+use crate::{AppConfig, Thumb, ThumbFormat, ThumbSize};
+
+pub const THUMB_DIR_NAME: &str = "thumbnails";
+
+const JPEG_QUALITY: u8 = 85;
+
+// Thumbnails are named after the content hash, so media sharing a file share
+// them too, and a thumbnail can never be stale for the media it belongs to.
+pub fn thumb_path(lib: &Library, media: &Media, size: &ThumbSize, format: &ThumbFormat) -> PathBuf {
+    PathBuf::from(lib.get_path())
+        .join(THUMB_DIR_NAME)
+        .join(size.pixels().to_string())
+        .join(format!("{}.{}", media.hash, format.extension()))
+}
+
+// EXIF orientation of the image, 1 being upright.
+fn orientation(media: &Media) -> u32 {
+    match &media.detail {
+        Some(MediaDetail::Image(v)) => v
+            .extra
+            .get("exif.Orientation")
+            .and_then(|v| v.parse::<u32>().ok())
+            .unwrap_or(1),
+        _ => 1,
+    }
+}
+
+// Turn the image upright as the camera recorded it in EXIF.
+fn apply_orientation(image: DynamicImage, orientation: u32) -> DynamicImage {
+    match orientation {
+        2 => image.fliph(),
+        3 => image.rotate180(),
+        4 => image.flipv(),
+        5 => image.rotate90().fliph(),
+        6 => image.rotate90(),
+        7 => image.rotate270().fliph(),
+        8 => image.rotate270(),
+        _ => image,
+    }
+}
+
+fn encode(image: &DynamicImage, format: &ThumbFormat, target: &Path) -> Result<(), Box<dyn Error>> {
+    let mut writer = BufWriter::new(File::create(target)?);
+    let (width, height) = image.dimensions();
+    match format {
+        ThumbFormat::Jpeg => {
+            let rgb = image.to_rgb8();
+            JpegEncoder::new_with_quality(&mut writer, JPEG_QUALITY).encode(
+                rgb.as_raw(),
+                width,
+                height,
+                ColorType::Rgb8,
+            )?;
+        }
+        ThumbFormat::Webp => {
+            let rgba = image.to_rgba8();
+            image_webp::WebPEncoder::new(&mut writer).encode(
+                rgba.as_raw(),
+                width,
+                height,
+                image_webp::ColorType::Rgba8,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+// Path of the thumbnail, made first when it is missing or when `rebuild` is
+// set. The flag tells whether it was made by this call.
+pub fn ensure_thumb(
+    lib: &Library,
+    media: &Media,
+    size: &ThumbSize,
+    format: &ThumbFormat,
+    rebuild: bool,
+) -> Result<(PathBuf, bool), Box<dyn Error>> {
+    if media.kind != MediaType::Image {
+        return Err(format!("{} is not an image.", media.filename).into());
+    }
+    let path = thumb_path(lib, media, size, format);
+    let source = media_file_path(lib, media);
+    let created = make_thumb(&source, &path, orientation(media), size, format, rebuild)?;
+    Ok((path, created))
+}
+
+fn make_thumb(
+    source: &Path,
+    target: &Path,
+    orientation: u32,
+    size: &ThumbSize,
+    format: &ThumbFormat,
+    rebuild: bool,
+) -> Result<bool, Box<dyn Error>> {
+    if target.exists() && !rebuild {
+        return Ok(false);
+    }
+    std::fs::create_dir_all(target.parent().unwrap())?;
+    let image = image::open(source)?;
+    let image = apply_orientation(image, orientation).thumbnail(size.pixels(), size.pixels());
+    // Written aside first, so an interrupted run never leaves half a file.
+    let partial = target.with_extension("partial");
+    if let Err(e) = encode(&image, format, &partial) {
+        std::fs::remove_file(&partial).unwrap_or(());
+        return Err(e);
+    }
+    std::fs::rename(&partial, target)?;
+    Ok(true)
+}
+
+// Thumbnails of the given sizes and format under `root` whose content is no
+// longer in the library. Those of other sizes and formats are left alone.
+fn remove_stale(
+    root: &Path,
+    sizes: &[ThumbSize],
+    format: &ThumbFormat,
+    hashes: &HashSet<&str>,
+) -> usize {
+    let files = sizes
+        .iter()
+        .filter_map(|size| std::fs::read_dir(root.join(size.pixels().to_string())).ok())
+        .flatten()
+        .filter_map(|f| f.ok().map(|f| f.path()))
+        .filter(|f| f.extension().and_then(|v| v.to_str()) == Some(format.extension()));
+    let mut removed = 0;
+    for file in files {
+        let stem = file
+            .file_stem()
+            .and_then(|v| v.to_str())
+            .unwrap_or_default();
+        if !hashes.contains(stem) && std::fs::remove_file(&file).is_ok() {
+            removed += 1;
+        }
+    }
+    removed
+}
+
+fn do_thumb_rebuild<F: Fn() -> bool>(
+    opt: &Thumb,
+    lib: &Library,
+    exit_checker: F,
+    out: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    let sizes = match &opt.size {
+        Some(v) => vec![v.clone()],
+        None => vec![ThumbSize::Small, ThumbSize::Medium, ThumbSize::Large],
+    };
+    let mut images = vec![];
+    for id in lib.query_media("1 = 1")? {
+        let media = lib.get_media(id)?;
+        if media.kind == MediaType::Image {
+            images.push(media);
+        }
+    }
+    let bar = if out.is_text() && console::user_attended() {
+        ProgressBar::new((images.len() * sizes.len()) as u64)
+    } else {
+        ProgressBar::hidden()
+    };
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} thumbnails, ETA {eta} {wide_msg}")
+            .progress_chars("#>-"),
+    );
+    let mut failed = 0;
+    for media in images.iter() {
+        bar.set_message(media.filename.clone());
+        for size in sizes.iter() {
+            match ensure_thumb(lib, media, size, &opt.format, true) {
+                Ok((path, created)) => out.emit(
+                    Record::Thumb(ThumbRecord::new(media.id, size, &path, created)),
+                    || (),
+                ),
+                Err(e) => {
+                    failed += 1;
+                    let message = format!("{} ({}): {}", media.filename, media.id, e);
+                    out.error(message.clone(), || {
+                        bar.println(format!("{}", STYLE_ERROR.apply_to(message)))
+                    });
+                }
+            }
+            bar.inc(1);
+        }
+        if exit_checker() {
+            bar.abandon_with_message("Interrupted.");
+            return Ok(());
+        }
+    }
+    bar.finish_with_message("Done.");
+    let stale = remove_stale(
+        &PathBuf::from(lib.get_path()).join(THUMB_DIR_NAME),
+        &sizes,
+        &opt.format,
+        &images.iter().map(|m| m.hash.as_str()).collect(),
+    );
+    if out.is_text() {
+        println!(
+            "{}",
+            STYLE_FIELD_VALUE.apply_to(format!(
+                "Rebuilt thumbnails of {} images, {} failed, {} stale removed.",
+                images.len(),
+                failed,
+                stale
+            ))
+        );
+    }
+    Ok(())
+}
+
+pub fn do_thumb<F: Fn() -> bool>(
+    opt: Thumb,
+    _cfg: AppConfig,
+    lib: &Library,
+    exit_checker: F,
+    out: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    if opt.rebuild {
+        return do_thumb_rebuild(&opt, lib, exit_checker, out);
+    }
+    let query_string = opt.media.clone().unwrap_or_default();
+    let media = find_media(lib, &query_string);
+    if media.is_empty() {
+        return Err(format!("Cannot acquire any media via: {}", query_string).into());
+    }
+    let size = opt.size.clone().unwrap_or(ThumbSize::Medium);
+    for m in media.iter() {
+        let (path, created) = ensure_thumb(lib, m, &size, &opt.format, false)?;
+        out.emit(
+            Record::Thumb(ThumbRecord::new(m.id, &size, &path, created)),
+            || println!("{}", path.to_str().unwrap_or_default()),
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Rgb};
+
+    #[test]
+    fn stale_thumbnails() {
+        let dir = tempfile::tempdir().unwrap();
+        for size in ["128", "256", "512"].iter() {
+            let sub = dir.path().join(size);
+            std::fs::create_dir(&sub).unwrap();
+            for name in ["kept.jpg", "gone.jpg", "gone.webp"].iter() {
+                std::fs::write(sub.join(name), b"").unwrap();
+            }
+        }
+        let hashes = ["kept"].iter().cloned().collect();
+        let sizes = [ThumbSize::Small, ThumbSize::Large];
+        assert_eq!(
+            remove_stale(dir.path(), &sizes, &ThumbFormat::Jpeg, &hashes),
+            2
+        );
+        assert!(dir.path().join("128/kept.jpg").exists());
+        assert!(!dir.path().join("128/gone.jpg").exists());
+        assert!(!dir.path().join("512/gone.jpg").exists());
+        // Sizes and formats which were not rebuilt keep their thumbnails.
+        assert!(dir.path().join("256/gone.jpg").exists());
+        assert!(dir.path().join("512/gone.webp").exists());
+        // A missing directory has nothing stale.
+        let root = dir.path().join("none");
+        assert_eq!(remove_stale(&root, &sizes, &ThumbFormat::Jpeg, &hashes), 0);
+    }
+
+    #[test]
+    fn thumbnails_are_made_once_and_upright() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("wide.png");
+        ImageBuffer::from_pixel(40, 20, Rgb([200u8, 0, 0]))
+            .save(&source)
+            .unwrap();
+        let target = dir.path().join("128").join("wide.jpg");
+        let size = ThumbSize::Small;
+        let made = |orientation, rebuild| {
+            make_thumb(
+                &source,
+                &target,
+                orientation,
+                &size,
+                &ThumbFormat::Jpeg,
+                rebuild,
+            )
+            .unwrap()
+        };
+
+        assert!(made(1, false));
+        let first = image::open(&target).unwrap().dimensions();
+        assert!(first.0 > first.1 && first.0 <= 128);
+        // An existing thumbnail is handed out as it is.
+        assert!(!made(6, false));
+        assert_eq!(image::open(&target).unwrap().dimensions(), first);
+        // Orientation 6 is turned a quarter, so the wide image stands up.
+        assert!(made(6, true));
+        let (width, height) = image::open(&target).unwrap().dimensions();
+        assert!(height > width && height <= 128);
+        assert!(!target.with_extension("partial").exists());
+    }
+
+    #[test]
+    fn broken_sources_leave_nothing() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("broken.png");
+        std::fs::write(&source, b"not an image").unwrap();
+        let target = dir.path().join("128").join("broken.jpg");
+        let size = ThumbSize::Small;
+        assert!(make_thumb(&source, &target, 1, &size, &ThumbFormat::Jpeg, false).is_err());
+        assert!(!target.exists());
+        assert!(!target.with_extension("partial").exists());
+    }
+}
@@ -0,0 +1,441 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::error::Error;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::time::UNIX_EPOCH;
+
+use ignore::WalkBuilder;
+use indicatif::{ProgressBar, ProgressStyle};
+use shiromana_rs::library::Library;
+use shiromana_rs::media::{Media, MediaType};
+use shiromana_rs::misc::HashAlgo;
+
+use crate::command::{media_file_path, STYLE_ERROR, STYLE_FIELD_NAME, STYLE_FIELD_VALUE};
+use crate::hasher::hash_file;
+use crate::output::{Output, Record, VerifyRecord};
+use crate::{AppConfig, Verify};
+
+pub const QUARANTINE_DIR_NAME: &str = "quarantine";
+
+// Modification times of stored files as of the last time their hash was found
+// right, keyed by the path of the media. `--quick` goes by these instead of
+// hashing.
+pub const STAMPS_NAME: &str = "verified.json";
+
+type Stamps = BTreeMap<String, u64>;
+
+enum Problem {
+    Missing,
+    Corrupted(String),
+    Orphan,
+}
+
+impl Problem {
+    fn name(&self) -> &'static str {
+        match self {
+            Problem::Missing => "missing",
+            Problem::Corrupted(_) => "corrupted",
+            Problem::Orphan => "orphan",
+        }
+    }
+}
+
+// Only the directories holding media are looked at for orphans, the library
+// keeps its own files next to them. Quarantined files are left alone, even
+// those of media which now point there.
+fn media_dirs(lib_path: &Path, media: &[Media]) -> Vec<PathBuf> {
+    let mut dirs: Vec<PathBuf> = media
+        .iter()
+        .filter_map(|m| Path::new(&m.filepath).components().next())
+        .filter(|c| c.as_os_str() != QUARANTINE_DIR_NAME)
+        .map(|c| lib_path.join(c.as_os_str()))
+        .filter(|p| p.is_dir())
+        .collect();
+    dirs.sort();
+    dirs.dedup();
+    dirs
+}
+
+fn find_orphans(lib_path: &Path, media: &[Media]) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let known: HashSet<PathBuf> = media.iter().map(|m| lib_path.join(&m.filepath)).collect();
+    let mut orphans = vec![];
+    for dir in media_dirs(lib_path, media) {
+        let walker = WalkBuilder::new(dir)
+            .standard_filters(false)
+            .sort_by_file_name(|a, b| a.cmp(b))
+            .build();
+        for entry in walker {
+            let entry = entry?;
+            let is_file = matches!(entry.file_type(), Some(v) if v.is_file());
+            if is_file && !known.contains(entry.path()) {
+                orphans.push(entry.into_path());
+            }
+        }
+    }
+    Ok(orphans)
+}
+
+fn load_stamps(lib_path: &Path) -> Stamps {
+    std::fs::read(lib_path.join(STAMPS_NAME))
+        .ok()
+        .and_then(|v| serde_json::from_slice(&v).ok())
+        .unwrap_or_default()
+}
+
+fn save_stamps(lib_path: &Path, stamps: &Stamps) -> Result<(), Box<dyn Error>> {
+    std::fs::write(lib_path.join(STAMPS_NAME), serde_json::to_vec(stamps)?)?;
+    Ok(())
+}
+
+fn modified_millis(meta: &Metadata) -> Option<u64> {
+    let since = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+    Some(since.as_millis() as u64)
+}
+
+// Without `algo`, as for `--quick`, only the size and the stamp are compared
+// and a file without a stamp is trusted by its size. Otherwise the file is
+// hashed, and its stamp is renewed when the hash is right.
+fn check_file(
+    path: &Path,
+    size: u64,
+    hash: &str,
+    algo: Option<&HashAlgo>,
+    stamp: &mut Option<u64>,
+) -> Result<Option<Problem>, Box<dyn Error>> {
+    let meta = match std::fs::metadata(path) {
+        Ok(v) => v,
+        Err(_) => return Ok(Some(Problem::Missing)),
+    };
+    if meta.len() != size {
+        *stamp = None;
+        return Ok(Some(Problem::Corrupted(format!(
+            "size is {} instead of {}",
+            meta.len(),
+            size
+        ))));
+    }
+    let modified = modified_millis(&meta);
+    let algo = match algo {
+        Some(v) => v,
+        None if stamp.is_some() && modified.is_some() && *stamp != modified => {
+            return Ok(Some(Problem::Corrupted(
+                "modified since it was last verified".to_string(),
+            )))
+        }
+        None => return Ok(None),
+    };
+    let actual = hash_file(path, algo)?;
+    if !actual.eq_ignore_ascii_case(hash) {
+        *stamp = None;
+        return Ok(Some(Problem::Corrupted(format!("hash is {}", actual))));
+    }
+    *stamp = modified;
+    Ok(None)
+}
+
+// Orphans of the size of some missing media are hashed, each of them once.
+fn hash_orphans(
+    orphans: &[PathBuf],
+    sizes: &HashSet<u64>,
+    algo: &HashAlgo,
+) -> HashMap<String, Vec<PathBuf>> {
+    let mut by_hash: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    for orphan in orphans.iter() {
+        match std::fs::metadata(orphan) {
+            Ok(v) if sizes.contains(&v.len()) => (),
+            _ => continue,
+        }
+        if let Ok(hash) = hash_file(orphan, algo) {
+            by_hash
+                .entry(hash.to_ascii_lowercase())
+                .or_default()
+                .push(orphan.clone());
+        }
+    }
+    by_hash
+}
+
+fn quarantine(lib_path: &Path, file: &Path) -> Result<PathBuf, Box<dyn Error>> {
+    let dir = lib_path.join(QUARANTINE_DIR_NAME);
+    std::fs::create_dir_all(&dir)?;
+    let rel = file.strip_prefix(lib_path).unwrap_or(file);
+    let target = dir.join(rel.to_str().unwrap_or_default().replace('/', "_"));
+    std::fs::rename(file, &target)?;
+    Ok(target)
+}
+
+fn relative(lib_path: &Path, file: &Path) -> String {
+    file.strip_prefix(lib_path)
+        .unwrap_or(file)
+        .to_str()
+        .unwrap_or_default()
+        .to_string()
+}
+
+pub fn do_verify<F: Fn() -> bool>(
+    opt: Verify,
+    _cfg: AppConfig,
+    lib: &mut Library,
+    exit_checker: F,
+    out: &mut Output,
+) -> Result<(), Box<dyn Error>> {
+    let lib_path = PathBuf::from(lib.get_path());
+    let mut media = vec![];
+    for id in lib.query_media("1 = 1")? {
+        let m = lib.get_media(id)?;
+        if m.kind != MediaType::URL {
+            media.push(m);
+        }
+    }
+
+    let bar = if out.is_text() && console::user_attended() {
+        ProgressBar::new(media.len() as u64)
+    } else {
+        ProgressBar::hidden()
+    };
+    bar.set_style(
+        ProgressStyle::default_bar()
+            .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} files, ETA {eta} {wide_msg}")
+            .progress_chars("#>-"),
+    );
+    let algo = lib.get_hash_algo();
+    let mut stamps = load_stamps(&lib_path);
+    let mut problems: Vec<(Option<&Media>, PathBuf, Problem)> = vec![];
+    for m in media.iter() {
+        bar.set_message(m.filename.clone());
+        let path = media_file_path(lib, m);
+        let mut stamp = stamps.get(&m.filepath).cloned();
+        let algo = if opt.quick { None } else { Some(&algo) };
+        match check_file(&path, m.filesize as u64, &m.hash, algo, &mut stamp) {
+            Ok(Some(problem)) => problems.push((Some(m), path, problem)),
+            Ok(None) => (),
+            Err(e) => problems.push((Some(m), path, Problem::Corrupted(e.to_string()))),
+        }
+        match stamp {
+            Some(v) => stamps.insert(m.filepath.clone(), v),
+            None => stamps.remove(&m.filepath),
+        };
+        bar.inc(1);
+        if exit_checker() {
+            bar.abandon_with_message("Interrupted.");
+            save_stamps(&lib_path, &stamps)?;
+            return Ok(());
+        }
+    }
+    bar.finish_and_clear();
+    // Stamps of media which are gone are dropped along the way.
+    let paths: HashSet<&str> = media.iter().map(|m| m.filepath.as_str()).collect();
+    stamps.retain(|k, _| paths.contains(k.as_str()));
+    save_stamps(&lib_path, &stamps)?;
+    let orphans = find_orphans(&lib_path, &media)?;
+
+    // A missing file may only have been moved inside the library, in which case
+    // it is one of the orphans and has the same content.
+    let mut relinked: HashMap<PathBuf, u64> = HashMap::new();
+    if opt.fix {
+        let missing: Vec<&Media> = problems
+            .iter()
+            .filter_map(|(m, _, problem)| match (m, problem) {
+                (Some(m), Problem::Missing) => Some(*m),
+                _ => None,
+            })
+            .collect();
+        let sizes = missing.iter().map(|m| m.filesize as u64).collect();
+        let mut by_hash = hash_orphans(&orphans, &sizes, &algo);
+        for m in missing {
+            if let Some(found) = by_hash
+                .get_mut(&m.hash.to_ascii_lowercase())
+                .and_then(|v| v.pop())
+            {
+                relinked.insert(found, m.id);
+            }
+        }
+    }
+    for orphan in orphans.into_iter() {
+        if !relinked.contains_key(&orphan) {
+            problems.push((None, orphan, Problem::Orphan));
+        }
+    }
+
+    for (m, path, problem) in problems.iter() {
+        let fixed = if !opt.fix {
+            None
+        } else {
+            let result: Result<Option<String>, Box<dyn Error>> = match (m, problem) {
+                (Some(m), Problem::Missing) => match relinked.iter().find(|(_, id)| **id == m.id) {
+                    Some((found, _)) => {
+                        let mut m = (*m).clone();
+                        m.filepath = relative(&lib_path, found);
+                        lib.update_media(&m)
+                            .map(|_| Some(format!("relinked to {}", m.filepath)))
+                            .map_err(|e| e.into())
+                    }
+                    None => Ok(None),
+                },
+                // The record follows the file, so it does not point at nothing.
+                (Some(m), Problem::Corrupted(_)) => {
+                    if path.starts_with(lib_path.join(QUARANTINE_DIR_NAME)) {
+                        Ok(Some("already quarantined".to_string()))
+                    } else {
+                        quarantine(&lib_path, path).and_then(|v| {
+                            let mut m = (*m).clone();
+                            m.filepath = relative(&lib_path, &v);
+                            if let Err(e) = lib.update_media(&m) {
+                                std::fs::rename(&v, path)?;
+                                return Err(e.into());
+                            }
+                            Ok(Some(format!(
+                                "quarantined to {}, the media now points there",
+                                m.filepath
+                            )))
+                        })
+                    }
+                }
+                (None, Problem::Orphan) => quarantine(&lib_path, path)
+                    .map(|v| Some(format!("quarantined to {}", relative(&lib_path, &v)))),
+                _ => Ok(None),
+            };
+            match result {
+                Ok(v) => v,
+                Err(e) => Some(format!("fix failed: {}", e)),
+            }
+        };
+        let detail = match problem {
+            Problem::Corrupted(v) => Some(v.clone()),
+            _ => None,
+        };
+        out.emit(
+            Record::Verify(VerifyRecord {
+                problem: problem.name().to_string(),
+                id: m.map(|m| m.id),
+                path: relative(&lib_path, path),
+                detail: detail.clone(),
+                fix: fixed.clone(),
+            }),
+            || {
+                println!(
+                    "{} {}{}{}",
+                    STYLE_ERROR.apply_to(format!("{:<9}", problem.name())),
+                    STYLE_FIELD_VALUE.apply_to(relative(&lib_path, path)),
+                    m.map(|m| format!(" (Media ID {})", m.id))
+                        .unwrap_or_default(),
+                    detail.map(|v| format!(", {}", v)).unwrap_or_default()
+                );
+                if let Some(v) = &fixed {
+                    println!("    {}", STYLE_FIELD_NAME.apply_to(v));
+                }
+            },
+        );
+    }
+
+    if out.is_text() {
+        println!(
+            "{}",
+            STYLE_FIELD_VALUE.apply_to(format!(
+                "Checked {} files, {} problems found.",
+                media.len(),
+                problems.len()
+            ))
+        );
+    }
+    if !problems.is_empty() {
+        out.finish();
+        exit(1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, name: &str, content: &[u8]) -> (PathBuf, String) {
+        let path = dir.join(name);
+        std::fs::write(&path, content).unwrap();
+        let hash = hash_file(&path, &HashAlgo::SHA256).unwrap();
+        (path, hash)
+    }
+
+    #[test]
+    fn full_check_renews_stamps() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, hash) = write(dir.path(), "a.txt", b"content");
+        let algo = Some(&HashAlgo::SHA256);
+        let mut stamp = None;
+        assert!(check_file(&path, 7, &hash, algo, &mut stamp)
+            .unwrap()
+            .is_none());
+        assert!(stamp.is_some());
+
+        std::fs::write(&path, b"CONTENT").unwrap();
+        match check_file(&path, 7, &hash, algo, &mut stamp).unwrap() {
+            Some(Problem::Corrupted(v)) => assert!(v.starts_with("hash is ")),
+            _ => panic!("changed content is not found"),
+        }
+        assert_eq!(stamp, None);
+    }
+
+    #[test]
+    fn quick_check_does_not_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, _) = write(dir.path(), "a.txt", b"content");
+        // A wrong hash goes unnoticed, only the size and the stamp count.
+        let mut stamp = None;
+        assert!(check_file(&path, 7, "bad", None, &mut stamp)
+            .unwrap()
+            .is_none());
+        match check_file(&path, 8, "bad", None, &mut stamp).unwrap() {
+            Some(Problem::Corrupted(v)) => assert_eq!(v, "size is 7 instead of 8"),
+            _ => panic!("wrong size is not found"),
+        }
+        let modified = modified_millis(&std::fs::metadata(&path).unwrap()).unwrap();
+        let mut stamp = Some(modified);
+        assert!(check_file(&path, 7, "bad", None, &mut stamp)
+            .unwrap()
+            .is_none());
+        let mut stamp = Some(modified + 1);
+        assert!(matches!(
+            check_file(&path, 7, "bad", None, &mut stamp).unwrap(),
+            Some(Problem::Corrupted(_))
+        ));
+        let mut stamp = None;
+        assert!(matches!(
+            check_file(&dir.path().join("b.txt"), 7, "bad", None, &mut stamp).unwrap(),
+            Some(Problem::Missing)
+        ));
+    }
+
+    #[test]
+    fn orphans_by_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let (a, hash) = write(dir.path(), "a", b"same");
+        let (b, _) = write(dir.path(), "b", b"same");
+        let (c, _) = write(dir.path(), "c", b"other content");
+        let sizes = [4].iter().cloned().collect();
+        let by_hash = hash_orphans(&[a.clone(), b.clone(), c], &sizes, &HashAlgo::SHA256);
+        // Orphans of other sizes are not hashed at all.
+        assert_eq!(by_hash.len(), 1);
+        assert_eq!(by_hash[&hash], vec![a, b]);
+    }
+
+    #[test]
+    fn stamps_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(load_stamps(dir.path()).is_empty());
+        let mut stamps = Stamps::new();
+        stamps.insert("ab/cd.jpg".to_string(), 1_600_000_000_000);
+        save_stamps(dir.path(), &stamps).unwrap();
+        assert_eq!(load_stamps(dir.path()), stamps);
+    }
+
+    #[test]
+    fn quarantined_names() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("ab")).unwrap();
+        let (path, _) = write(&dir.path().join("ab"), "cd.jpg", b"x");
+        let target = quarantine(dir.path(), &path).unwrap();
+        assert_eq!(relative(dir.path(), &target), "quarantine/ab_cd.jpg");
+        assert!(!path.exists());
+    }
+}